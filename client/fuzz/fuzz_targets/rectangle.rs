@@ -0,0 +1,9 @@
+#![no_main]
+
+use einkvnc::vnc::fuzz::{Message, Rectangle};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    while Rectangle::read_from(&mut reader).is_ok() {}
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use einkvnc::vnc::fuzz::{Message, S2C};
+use libfuzzer_sys::fuzz_target;
+
+// Server messages one after the other, until one doesn't parse.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    while S2C::read_from(&mut reader).is_ok() {}
+});
@@ -0,0 +1,33 @@
+#![no_main]
+
+use einkvnc::vnc::fuzz::{Message, ZrleDecoder};
+use einkvnc::vnc::{PixelFormat, Rect};
+use libfuzzer_sys::fuzz_target;
+
+// A pixel format and the size of a rectangle up to 128x128, then zlib data
+// for it.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    let format = match PixelFormat::read_from(&mut reader) {
+        Ok(format) => format,
+        Err(_) => return,
+    };
+    if reader.len() < 2 {
+        return;
+    }
+    let rect = Rect {
+        left: 0,
+        top: 0,
+        width: reader[0] as u16 % 128 + 1,
+        height: reader[1] as u16 % 128 + 1,
+    };
+    let bytes_per_pixel = format.bits_per_pixel as usize / 8;
+    let _ = ZrleDecoder::new().decode(format, rect, &reader[2..], |tile, pixels| {
+        assert!(tile.left + tile.width <= rect.width && tile.top + tile.height <= rect.height);
+        assert_eq!(
+            pixels.len(),
+            tile.width as usize * tile.height as usize * bytes_per_pixel
+        );
+        Ok(true)
+    });
+});
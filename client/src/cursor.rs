@@ -0,0 +1,153 @@
+use crate::framebuffer::Framebuffer;
+use crate::geom::Rectangle;
+
+// The remote cursor, drawn locally on top of the desktop. The pixels it
+// covers are kept aside so it can be lifted off again before the desktop
+// underneath is updated or the cursor moves.
+pub struct Cursor {
+    size: (u32, u32),
+    hotspot: (i32, i32),
+    // One gray level per pixel and the RFB mask, one bit per pixel with
+    // rows padded to a whole byte.
+    pixels: Vec<u8>,
+    mask_bits: Vec<u8>,
+    position: Option<(i32, i32)>,
+    under: Option<(Rectangle, Vec<u8>)>,
+}
+
+impl Cursor {
+    pub fn new() -> Cursor {
+        Cursor {
+            size: (0, 0),
+            hotspot: (0, 0),
+            pixels: Vec::new(),
+            mask_bits: Vec::new(),
+            position: None,
+            under: None,
+        }
+    }
+
+    // Takes the shape with pixels already converted to gray. The cursor must
+    // be hidden first.
+    pub fn set_shape(
+        &mut self,
+        size: (u16, u16),
+        hotspot: (u16, u16),
+        pixels: Vec<u8>,
+        mask_bits: Vec<u8>,
+    ) {
+        self.size = (size.0 as u32, size.1 as u32);
+        self.hotspot = (hotspot.0 as i32, hotspot.1 as i32);
+        self.pixels = pixels;
+        self.mask_bits = mask_bits;
+    }
+
+    // Where the hotspot goes. The cursor must be hidden first.
+    pub fn move_to(&mut self, x: u16, y: u16) {
+        self.position = Some((x as i32, y as i32));
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.under.is_some()
+    }
+
+    // The area the cursor covers, or would cover if it were shown.
+    pub fn rect(&self) -> Option<Rectangle> {
+        let (x, y) = self.position?;
+        if self.size.0 == 0 || self.size.1 == 0 {
+            return None;
+        }
+        let left = x - self.hotspot.0;
+        let top = y - self.hotspot.1;
+        Some(rect![
+            left,
+            top,
+            left + self.size.0 as i32,
+            top + self.size.1 as i32
+        ])
+    }
+
+    // Draws the cursor within `bounds` and returns the area to refresh.
+    pub fn show(&mut self, fb: &mut dyn Framebuffer, bounds: &Rectangle) -> Option<Rectangle> {
+        if self.is_shown() {
+            return None;
+        }
+        let rect = self.rect()?;
+        let clipped = rect.intersection(bounds)?;
+
+        let stride = (self.size.0 as usize).div_ceil(8);
+        let mut under = Vec::with_capacity(clipped.area() as usize);
+        for y in clipped.min.y..clipped.max.y {
+            for x in clipped.min.x..clipped.max.x {
+                under.push(fb.get_pixel(x as u32, y as u32));
+
+                let column = (x - rect.min.x) as usize;
+                let row = (y - rect.min.y) as usize;
+                if self.mask_bits[row * stride + column / 8] & (0x80 >> (column % 8)) != 0 {
+                    let color = self.pixels[row * self.size.0 as usize + column];
+                    fb.set_pixel(x as u32, y as u32, color);
+                }
+            }
+        }
+        self.under = Some((clipped, under));
+        Some(clipped)
+    }
+
+    // Hides the cursor if it's drawn anywhere in `rect`.
+    pub fn hide_within(&mut self, fb: &mut dyn Framebuffer, rect: &Rectangle) -> Option<Rectangle> {
+        match self.under {
+            Some((ref under_rect, _)) if under_rect.overlaps(rect) => self.hide(fb),
+            _ => None,
+        }
+    }
+
+    // Puts back what the cursor covered and returns the area to refresh.
+    pub fn hide(&mut self, fb: &mut dyn Framebuffer) -> Option<Rectangle> {
+        let (rect, under) = self.under.take()?;
+        let mut pixels = under.into_iter();
+        for y in rect.min.y..rect.max.y {
+            for x in rect.min.x..rect.max.x {
+                fb.set_pixel(x as u32, y as u32, pixels.next().unwrap());
+            }
+        }
+        Some(rect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::framebuffer::{Framebuffer, Pixmap};
+
+    #[test]
+    fn test_show_and_hide() {
+        let mut fb = Pixmap::new(4, 4);
+        fb.clear(200);
+        fb.set_pixel(0, 0, 7);
+        let bounds = fb.rect();
+
+        let mut cursor = Cursor::new();
+        // A 2x2 arrow with its hotspot at the top left and the bottom right
+        // pixel masked out.
+        cursor.set_shape(
+            (2, 2),
+            (0, 0),
+            vec![1, 2, 3, 4],
+            vec![0b1100_0000, 0b1000_0000],
+        );
+        assert_eq!(cursor.show(&mut fb, &bounds), None);
+
+        cursor.move_to(3, 3);
+        assert_eq!(cursor.show(&mut fb, &bounds), Some(rect![3, 3, 4, 4]));
+        assert_eq!(fb.get_pixel(3, 3), 1);
+        assert_eq!(cursor.hide(&mut fb), Some(rect![3, 3, 4, 4]));
+        assert_eq!(fb.get_pixel(3, 3), 200);
+
+        cursor.move_to(0, 0);
+        cursor.show(&mut fb, &bounds);
+        assert_eq!([0, 1, 4, 5].map(|i| fb.data()[i]), [1, 2, 3, 200]);
+        cursor.hide(&mut fb);
+        assert_eq!([0, 1, 4, 5].map(|i| fb.data()[i]), [7, 200, 200, 200]);
+        assert!(!cursor.is_shown());
+    }
+}
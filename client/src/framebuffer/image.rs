@@ -1,6 +1,6 @@
-use super::{Framebuffer, UpdateMode};
+use super::{clip_blit, clip_copy, move_rows, Framebuffer, UpdateMode};
 use crate::color::WHITE;
-use crate::geom::{lerp, Rectangle};
+use crate::geom::{lerp, Point, Rectangle};
 use anyhow::{format_err, Context, Error};
 use std::fs::File;
 use std::path::Path;
@@ -62,7 +62,7 @@ impl Pixmap {
 
     #[inline]
     pub fn get_pixel(&self, x: u32, y: u32) -> u8 {
-        if self.data.is_empty() {
+        if x >= self.width || y >= self.height || self.data.is_empty() {
             return WHITE;
         }
         let addr = (y * self.width + x) as usize;
@@ -83,8 +83,7 @@ impl Framebuffer for Pixmap {
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> u8 {
-        let addr = (y * self.width + x) as usize;
-        return self.data[addr];
+        Pixmap::get_pixel(self, x, y)
     }
 
     fn set_blended_pixel(&mut self, x: u32, y: u32, color: u8, alpha: f32) {
@@ -103,6 +102,36 @@ impl Framebuffer for Pixmap {
         self.data[addr] = blended_color;
     }
 
+    fn blit_gray(&mut self, rect: &Rectangle, pixels: &[u8], stride: usize) {
+        let (clipped, start) = match clip_blit(&self.rect(), rect, stride) {
+            Some(clip) if !self.data.is_empty() => clip,
+            _ => return,
+        };
+        let width = clipped.width() as usize;
+        let rows = self.data[clipped.min.y as usize * self.width as usize..]
+            .chunks_mut(self.width as usize)
+            .zip(pixels[start..].chunks(stride));
+        for (row, pixels) in rows.take(clipped.height() as usize) {
+            row[clipped.min.x as usize..][..width].copy_from_slice(&pixels[..width]);
+        }
+    }
+
+    fn copy_region(&mut self, src: &Rectangle, dst: Point) {
+        let (src, dst) = match clip_copy(&self.rect(), src, dst) {
+            Some(clip) if !self.data.is_empty() => clip,
+            _ => return,
+        };
+        let width = self.width as usize;
+        move_rows(
+            &mut self.data,
+            width,
+            src.min.y as usize * width + src.min.x as usize,
+            dst.y as usize * width + dst.x as usize,
+            src.width() as usize,
+            src.height() as usize,
+        );
+    }
+
     fn invert_region(&mut self, rect: &Rectangle) {
         if self.data.is_empty() {
             return;
@@ -186,21 +215,3 @@ impl Framebuffer for Pixmap {
         self.height
     }
 }
-
-#[derive(Debug, Clone)]
-pub struct ReadonlyPixmap<'a> {
-    pub width: u32,
-    pub height: u32,
-    pub data: &'a Vec<u8>,
-}
-
-impl<'a> ReadonlyPixmap<'a> {
-    #[inline]
-    pub fn get_pixel(&self, x: u32, y: u32) -> u8 {
-        if self.data.is_empty() {
-            return WHITE;
-        }
-        let addr = (y * self.width + x) as usize;
-        self.data[addr]
-    }
-}
@@ -6,9 +6,9 @@ use std::slice;
 use std::os::unix::io::AsRawFd;
 use std::ops::Drop;
 use anyhow::{Error, Context};
-use crate::geom::Rectangle;
+use crate::geom::{Point, Rectangle};
 use crate::device::{CURRENT_DEVICE, Model};
-use super::{UpdateMode, Framebuffer};
+use super::{UpdateMode, Framebuffer, clip_blit, clip_copy, move_rows};
 use super::linuxfb_sys::*;
 use super::mxcfb_sys::*;
 use super::transform::*;
@@ -100,6 +100,16 @@ impl KoboFramebuffer1 {
     fn as_bytes(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self.frame as *const u8, self.frame_size) }
     }
+
+    // All of the mapped memory, panning offsets included.
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.frame as *mut u8, self.fix_info.smem_len as usize) }
+    }
+
+    fn addr(&self, x: u32, y: u32) -> usize {
+        (self.var_info.xoffset + x) as usize * self.bytes_per_pixel as usize +
+        (self.var_info.yoffset + y) as usize * self.fix_info.line_length as usize
+    }
 }
 
 impl Framebuffer for KoboFramebuffer1 {
@@ -125,6 +135,55 @@ impl Framebuffer for KoboFramebuffer1 {
         (self.set_pixel_rgb)(self, x, y, [c, c, c]);
     }
 
+    // The driver does the rotating, so rows are rows whatever the rotation,
+    // and only the pixel size matters.
+    fn blit_gray(&mut self, rect: &Rectangle, pixels: &[u8], stride: usize) {
+        let (clipped, start) = match clip_blit(&self.rect(), rect, stride) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let (left, top) = (clipped.min.x as u32, clipped.min.y as u32);
+        let width = clipped.width() as usize;
+        let bytes_per_pixel = self.bytes_per_pixel as usize;
+        let line_length = self.fix_info.line_length as usize;
+        let addr = self.addr(left, top);
+        let transform = self.transform;
+        let dithered = self.dithered;
+        let frame = self.as_bytes_mut();
+        for (i, pixels) in pixels[start..].chunks(stride).take(clipped.height() as usize).enumerate() {
+            let row = &mut frame[addr + i * line_length..][..width * bytes_per_pixel];
+            let pixels = &pixels[..width];
+            let y = top + i as u32;
+            match bytes_per_pixel {
+                1 if !dithered => row.copy_from_slice(pixels),
+                1 => for (x, (spot, &c)) in row.iter_mut().zip(pixels).enumerate() {
+                    *spot = transform(left + x as u32, y, c);
+                },
+                2 => for (x, (spot, &c)) in row.chunks_exact_mut(2).zip(pixels).enumerate() {
+                    let c = transform(left + x as u32, y, c);
+                    spot[0] = c >> 3 | (c & 0b0001_1100) << 3;
+                    spot[1] = (c & 0b1111_1000) | c >> 5;
+                },
+                _ => for (x, (spot, &c)) in row.chunks_exact_mut(bytes_per_pixel).zip(pixels).enumerate() {
+                    let c = transform(left + x as u32, y, c);
+                    spot[..3].fill(c);
+                },
+            }
+        }
+    }
+
+    fn copy_region(&mut self, src: &Rectangle, dst: Point) {
+        let (src, dst) = match clip_copy(&self.rect(), src, dst) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let line_length = self.fix_info.line_length as usize;
+        let from = self.addr(src.min.x as u32, src.min.y as u32);
+        let to = self.addr(dst.x as u32, dst.y as u32);
+        let width = src.width() as usize * self.bytes_per_pixel as usize;
+        move_rows(self.as_bytes_mut(), line_length, from, to, width, src.height() as usize);
+    }
+
     fn invert_region(&mut self, rect: &Rectangle) {
         for y in rect.min.y..rect.max.y {
             for x in rect.min.x..rect.max.x {
@@ -8,9 +8,9 @@ use std::fs::File;
 use std::os::unix::io::AsRawFd;
 use std::ops::Drop;
 use anyhow::{Error, Context};
-use crate::geom::Rectangle;
+use crate::geom::{Point, Rectangle};
 use crate::device::CURRENT_DEVICE;
-use super::{UpdateMode, Framebuffer};
+use super::{UpdateMode, Framebuffer, clip_blit, clip_copy, move_rows};
 use super::linuxfb_sys::*;
 use super::ion_sys::*;
 use super::sunxi_sys::*;
@@ -220,6 +220,9 @@ impl KoboFramebuffer2 {
         unsafe { slice::from_raw_parts(self.frame as *const u8, self.frame_size) }
     }
 
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.frame as *mut u8, self.frame_size) }
+    }
 }
 
 impl Framebuffer for KoboFramebuffer2 {
@@ -255,6 +258,43 @@ impl Framebuffer for KoboFramebuffer2 {
         self.set_pixel(x, y, c);
     }
 
+    fn blit_gray(&mut self, rect: &Rectangle, pixels: &[u8], stride: usize) {
+        let (clipped, start) = match clip_blit(&self.rect(), rect, stride) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let (left, top) = (clipped.min.x as u32, clipped.min.y as u32);
+        let width = clipped.width() as usize;
+        let line_length = self.fix_info.line_length as usize;
+        let addr = left as usize + top as usize * line_length;
+        let (transform, dithered, inverted) = (self.transform, self.dithered, self.inverted);
+        let frame = self.as_bytes_mut();
+        for (i, pixels) in pixels[start..].chunks(stride).take(clipped.height() as usize).enumerate() {
+            let row = &mut frame[addr + i * line_length..][..width];
+            let pixels = &pixels[..width];
+            if !dithered && !inverted {
+                row.copy_from_slice(pixels);
+                continue;
+            }
+            let y = top + i as u32;
+            for (x, (spot, &c)) in row.iter_mut().zip(pixels).enumerate() {
+                let c = transform(left + x as u32, y, c);
+                *spot = if inverted { 255 - c } else { c };
+            }
+        }
+    }
+
+    fn copy_region(&mut self, src: &Rectangle, dst: Point) {
+        let (src, dst) = match clip_copy(&self.rect(), src, dst) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let line_length = self.fix_info.line_length as usize;
+        let from = src.min.x as usize + src.min.y as usize * line_length;
+        let to = dst.x as usize + dst.y as usize * line_length;
+        move_rows(self.as_bytes_mut(), line_length, from, to, src.width() as usize, src.height() as usize);
+    }
+
     fn invert_region(&mut self, rect: &Rectangle) {
         for y in rect.min.y..rect.max.y {
             for x in rect.min.x..rect.max.x {
@@ -85,6 +85,10 @@ pub trait Framebuffer {
     }
 
     fn draw_rectangle(&mut self, rect: &Rectangle, color: u8) {
+        let rect = match rect.intersection(&self.rect()) {
+            Some(rect) => rect,
+            None => return,
+        };
         for y in rect.min.y..rect.max.y {
             for x in rect.min.x..rect.max.x {
                 self.set_pixel(x as u32, y as u32, color);
@@ -92,6 +96,38 @@ pub trait Framebuffer {
         }
     }
 
+    // Draws rows of gray pixels into `rect`, each row starting `stride` bytes
+    // after the previous one in `pixels`. What falls off the framebuffer is
+    // left out.
+    fn blit_gray(&mut self, rect: &Rectangle, pixels: &[u8], stride: usize) {
+        let (clipped, start) = match clip_blit(&self.rect(), rect, stride) {
+            Some(clip) => clip,
+            None => return,
+        };
+        for (y, row) in (clipped.min.y..clipped.max.y).zip(pixels[start..].chunks(stride)) {
+            for (x, &color) in (clipped.min.x..clipped.max.x).zip(row) {
+                self.set_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+
+    // Copies `src` to the rectangle of the same size at `dst`, which may
+    // overlap it.
+    fn copy_region(&mut self, src: &Rectangle, dst: Point) {
+        let (src, dst) = match clip_copy(&self.rect(), src, dst) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let mut pixels = Vec::with_capacity(src.width() as usize * src.height() as usize);
+        for y in src.min.y..src.max.y {
+            for x in src.min.x..src.max.x {
+                pixels.push(self.get_pixel(x as u32, y as u32));
+            }
+        }
+        let dst = rect![dst, dst + pt!(src.width() as i32, src.height() as i32)];
+        self.blit_gray(&dst, &pixels, src.width() as usize);
+    }
+
     fn draw_blended_rectangle(&mut self, rect: &Rectangle, color: u8, alpha: f32) {
         for y in rect.min.y..rect.max.y {
             for x in rect.min.x..rect.max.x {
@@ -464,3 +500,62 @@ pub trait Framebuffer {
         }
     }
 }
+
+// The part of `rect` within `bounds`, and where its pixels start in a buffer
+// of rows `stride` bytes apart.
+fn clip_blit(bounds: &Rectangle, rect: &Rectangle, stride: usize) -> Option<(Rectangle, usize)> {
+    let clipped = bounds.intersection(rect)?;
+    let start =
+        (clipped.min.y - rect.min.y) as usize * stride + (clipped.min.x - rect.min.x) as usize;
+    Some((clipped, start))
+}
+
+// The part of a copy from `src` to `dst` that stays within `bounds` at both
+// ends.
+fn clip_copy(bounds: &Rectangle, src: &Rectangle, dst: Point) -> Option<(Rectangle, Point)> {
+    let offset = dst - src.min;
+    let dst = (bounds.intersection(src)? + offset).intersection(bounds)?;
+    Some((dst - offset, dst.min))
+}
+
+// Moves `height` rows of `width` bytes from offset `src` to offset `dst` of a
+// frame with rows `line_length` bytes apart, overlapping or not.
+fn move_rows(
+    frame: &mut [u8],
+    line_length: usize,
+    src: usize,
+    dst: usize,
+    width: usize,
+    height: usize,
+) {
+    for i in 0..height {
+        // Bottom up when moving down, so no row is overwritten before it's moved.
+        let row = if dst > src { height - 1 - i } else { i };
+        let offset = row * line_length;
+        frame.copy_within(src + offset..src + offset + width, dst + offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framebuffer, Pixmap};
+    use crate::{pt, rect};
+
+    #[test]
+    fn test_blit_and_copy() {
+        let mut pixmap = Pixmap::new(4, 3);
+        // Half of it off the right edge.
+        pixmap.blit_gray(&rect![2, 0, 6, 2], &[1, 2, 3, 4, 0, 5, 6, 7, 8, 0], 5);
+        assert_eq!(
+            pixmap.data,
+            [255, 255, 1, 2, 255, 255, 5, 6, 255, 255, 255, 255]
+        );
+
+        // Down and left, over itself.
+        pixmap.copy_region(&rect![1, 0, 4, 2], pt!(0, 1));
+        assert_eq!(pixmap.data, [255, 255, 1, 2, 255, 1, 2, 6, 255, 5, 6, 255]);
+        // Off the edges, at either end.
+        pixmap.copy_region(&rect![2, 1, 5, 3], pt!(-1, 0));
+        assert_eq!(pixmap.data, [6, 255, 1, 2, 255, 1, 2, 6, 255, 5, 6, 255]);
+    }
+}
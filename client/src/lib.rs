@@ -0,0 +1,10 @@
+// The protocol half of einkvnc as a library, for the fuzz targets. Nothing
+// else links against it, so it's only built for fuzzing.
+#![cfg(fuzzing)]
+#![allow(dead_code, unused_imports)]
+
+#[macro_use]
+extern crate log;
+
+mod security;
+pub mod vnc;
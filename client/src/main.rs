@@ -8,17 +8,29 @@ mod framebuffer;
 #[macro_use]
 mod geom;
 mod color;
+mod cursor;
 mod input;
+mod render;
 mod security;
 mod settings;
 mod vnc;
 
-pub use crate::framebuffer::image::ReadonlyPixmap;
+use crate::render::Renderer;
 use crate::framebuffer::{Framebuffer, KoboFramebuffer1, KoboFramebuffer2, Pixmap, UpdateMode};
 use crate::geom::Rectangle;
-use crate::vnc::{client, Client, Encoding, Rect};
+use crate::vnc::client::TlsConfig;
+use crate::vnc::proxy::Proxy;
+use crate::vnc::{client, fbs, gray, Client, Encoding, GrayConverter, LumaWeights, Rect, Transcode};
 use clap::{value_t, App, Arg};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
@@ -26,15 +38,10 @@ use vnc::PixelFormat;
 
 use anyhow::{Context as ResultExt, Error};
 
-use crate::device::CURRENT_DEVICE;
+use crate::device::{Orientation, CURRENT_DEVICE};
 
 const FB_DEVICE: &str = "/dev/fb0";
 
-#[repr(align(256))]
-pub struct PostProcBin {
-    data: [u8; 256],
-}
-
 fn main() -> Result<(), Error> {
     env_logger::init();
 
@@ -43,25 +50,157 @@ fn main() -> Result<(), Error> {
         .arg(
             Arg::with_name("HOST")
                 .help("server hostname or IP")
-                .required(true)
+                .required_unless_one(["UNIX", "VIA", "LISTEN"])
                 .index(1),
         )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            App::new("proxy")
+                .about("run on the PC to relay a server to einkvnc, doing the gray conversion, contrast and dithering on the way")
+                .arg(
+                    Arg::with_name("HOST")
+                        .help("server hostname or IP")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PORT")
+                        .help("server port (default: 5900)")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("LISTEN")
+                        .help("port to accept einkvnc on (default: 5901)")
+                        .long("listen")
+                        .value_name("PORT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("BIND")
+                        .help("address to accept einkvnc on (default: all of them with a viewer password, otherwise 127.0.0.1); without a viewer password only loopback and USB network addresses are allowed")
+                        .long("bind")
+                        .value_name("ADDRESS")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("VIEWERPASSWORD")
+                        .help("password einkvnc has to give the proxy, instead of the server's")
+                        .long("viewer-password")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("VIEWERPASSWDFILE")
+                        .help("read the viewer password from a file made by vncpasswd")
+                        .long("viewer-passwd-file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .conflicts_with("VIEWERPASSWORD"),
+                )
+                .arg(
+                    Arg::with_name("NODITHER")
+                        .help("round to the nearest of the 16 gray levels instead of dithering")
+                        .long("no-dither"),
+                ),
+        )
+        .subcommand(
+            App::new("replay")
+                .about("draw a recorded session the way einkvnc would, to a PNG per frame and a log of the panel refreshes")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("FBS recording, e.g. made with --record")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("directory to write to (default: the recording's name without the extension)")
+                        .long("output")
+                        .value_name("DIR")
+                        .takes_value(true),
+                ),
+        )
         .arg(
             Arg::with_name("PORT")
                 .help("server port (default: 5900)")
                 .index(2),
         )
+        .arg(
+            Arg::with_name("LISTEN")
+                .help("wait for the server to connect to us instead (default port: 5500)")
+                .long("listen")
+                .value_name("PORT")
+                .takes_value(true)
+                .min_values(0)
+                .conflicts_with_all(&["HOST", "UNIX", "VIA"]),
+        )
+        .arg(
+            Arg::with_name("UNIX")
+                .help("connect to the server's Unix domain socket instead")
+                .long("unix")
+                .takes_value(true)
+                .conflicts_with_all(&["HOST", "VIA"]),
+        )
+        .arg(
+            Arg::with_name("VIA")
+                .help("talk to the server through the input and output of a shell command, e.g. \"ssh host nc localhost 5900\"")
+                .long("via")
+                .takes_value(true)
+                .conflicts_with("HOST"),
+        )
         .arg(
             Arg::with_name("USERNAME")
                 .help("server username")
                 .long("username")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("PASSWORD")
-                .help("server password")
+                .help("server password, visible to other users of the device; see the options below")
                 .long("password")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("PASSWDFILE")
+                .help("read the password from a file made by vncpasswd, e.g. ~/.vnc/passwd")
+                .long("passwd-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["PASSWORD", "PASSWORDENV", "PASSWORDSTDIN"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("PASSWORDENV")
+                .help("read the password from an environment variable")
+                .long("password-env")
+                .value_name("VAR")
+                .takes_value(true)
+                .conflicts_with_all(&["PASSWORD", "PASSWORDSTDIN"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("PASSWORDSTDIN")
+                .help("read the password from the first line of standard input")
+                .long("password-stdin")
+                .conflicts_with("PASSWORD")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("TLSCA")
+                .help("CA certificates to verify the server's TLS certificate against, instead of pinning it on first use")
+                .long("tls-ca")
+                .value_name("FILE")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("KNOWNHOSTS")
+                .help("where the fingerprints of server certificates are pinned (default: ~/.config/einkvnc/known_hosts)")
+                .long("known-hosts")
+                .value_name("FILE")
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("EXCLUSIVE")
@@ -72,87 +211,171 @@ fn main() -> Result<(), Error> {
             Arg::with_name("CONTRAST")
                 .help("apply a post processing contrast filter")
                 .long("contrast")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("GRAYPOINT")
                 .help("the gray point of the post processing contrast filter")
                 .long("graypoint")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("WHITECUTOFF")
                 .help("apply a post processing filter to turn colors greater than the specified value to white (255)")
                 .long("whitecutoff")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         ).arg(
             Arg::with_name("ROTATE")
                 .help("rotation (1-4), tested on a Clara HD, try at own risk")
                 .long("rotate")
                 .takes_value(true),
-        ) 
+        )
+        .arg(
+            Arg::with_name("LUMA")
+                .help("weights used to turn colours into gray levels")
+                .long("luma")
+                .possible_values(["601", "709"])
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("GRAY8")
+                .help("ask the server for 8 bpp pixels of the green channel only, for less bandwidth; pure red or blue then shows as black")
+                .long("gray8"),
+        )
+        .arg(
+            Arg::with_name("NORECONNECT")
+                .help("exit when the connection drops instead of reconnecting")
+                .long("no-reconnect"),
+        )
+        .arg(
+            Arg::with_name("NORESIZE")
+                .help("don't ask the server to resize its desktop to match the screen")
+                .long("no-resize"),
+        )
+        .arg(
+            Arg::with_name("COMPRESSLEVEL")
+                .help("Tight compression level (0-9), lower is faster for the server")
+                .long("compresslevel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("QUALITY")
+                .help("Tight JPEG quality level (0-9), allows the server to send JPEG rectangles")
+                .long("quality")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("RECORD")
+                .help("record what the server sends to an FBS file, one numbered file per connection after the first")
+                .long("record")
+                .value_name("FILE")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("MAXLENGTH")
+                .help("largest clipboard text or block of compressed pixels to accept from the server, in bytes (default: 32 MiB)")
+                .long("max-length")
+                .value_name("BYTES")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("MAXPIXELS")
+                .help("largest framebuffer or cursor to accept from the server, in pixels (default: 8192x8192)")
+                .long("max-pixels")
+                .value_name("COUNT")
+                .takes_value(true)
+                .global(true),
+        )
         .get_matches();
 
-    let host = matches.value_of("HOST").unwrap();
+    if let Some(matches) = matches.subcommand_matches("proxy") {
+        set_limits(matches);
+        return run_proxy(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        set_limits(matches);
+        return run_replay(matches);
+    }
+    set_limits(&matches);
+
+    let host = matches.value_of("HOST");
     let port = value_t!(matches.value_of("PORT"), u16).unwrap_or(5900);
+    let unix_path = matches.value_of("UNIX");
+    let via = matches.value_of("VIA");
+    let listen_port = if matches.is_present("LISTEN") {
+        Some(value_t!(matches.value_of("LISTEN"), u16).unwrap_or(5500))
+    } else {
+        None
+    };
     let username = matches.value_of("USERNAME");
-    let password = matches.value_of("PASSWORD");
-    let contrast_exp = value_t!(matches.value_of("CONTRAST"), f32).unwrap_or(1.0);
-    let contrast_gray_point = value_t!(matches.value_of("GRAYPOINT"), f32).unwrap_or(224.0);
-    let white_cutoff = value_t!(matches.value_of("WHITECUTOFF"), u8).unwrap_or(255);
-    let exclusive = matches.is_present("EXCLUSIVE");
-    let rotate = value_t!(matches.value_of("ROTATE"), i8).unwrap_or(1);
-
-    info!("connecting to {}:{}", host, port);
-    let stream = match std::net::TcpStream::connect((host, port)) {
-        Ok(stream) => stream,
+    let password = match read_password(&matches) {
+        Ok(password) => password,
         Err(error) => {
-            error!("cannot connect to {}:{}: {}", host, port, error);
+            error!("{:#}", error);
             std::process::exit(1)
         }
     };
+    let password = password.as_deref();
+    let tls = tls_config(&matches);
+    let (luma, contrast_curve) = gray_options(&matches);
+    let exclusive = matches.is_present("EXCLUSIVE");
+    let rotate = value_t!(matches.value_of("ROTATE"), i8).unwrap_or(1);
+    let resize_desktop = !matches.is_present("NORESIZE");
+    let auto_reconnect = !matches.is_present("NORECONNECT");
+    let gray8 = matches.is_present("GRAY8");
+    let record = matches.value_of("RECORD").map(PathBuf::from);
+    let mut session = 0;
+    let compress_level = value_t!(matches.value_of("COMPRESSLEVEL"), u8).ok();
+    let quality_level = value_t!(matches.value_of("QUALITY"), u8).ok();
 
-    let mut vnc = match Client::from_tcp_stream(stream, !exclusive, |methods| {
-        debug!("available authentication methods: {:?}", methods);
-        for method in methods {
-            match method {
-                client::AuthMethod::None => return Some(client::AuthChoice::None),
-                client::AuthMethod::Password => {
-                    return match password {
-                        None => None,
-                        Some(ref password) => {
-                            let mut key = [0; 8];
-                            for (i, byte) in password.bytes().enumerate() {
-                                if i == 8 {
-                                    break;
-                                }
-                                key[i] = byte
-                            }
-                            Some(client::AuthChoice::Password(key))
-                        }
-                    }
-                }
-                client::AuthMethod::AppleRemoteDesktop => match (username, password) {
-                    (Some(username), Some(password)) => {
-                        return Some(client::AuthChoice::AppleRemoteDesktop(
-                            username.to_owned(),
-                            password.to_owned(),
-                        ))
-                    }
-                    _ => (),
-                },
-            }
-        }
-        None
-    }) {
+    let auth = |methods: &[client::AuthMethod]| auth_choice(methods, username, password);
+
+    let mut target = if let Some(path) = unix_path {
+        Target::Unix(path)
+    } else if let Some(command) = via {
+        Target::Via(command, None)
+    } else if let Some(port) = listen_port {
+        Target::Listen(port, None)
+    } else {
+        Target::Tcp(host.unwrap(), port)
+    };
+
+    let mut encodings = vec![
+        Encoding::Tight,
+        Encoding::CopyRect,
+        Encoding::Zrle,
+        Encoding::Trle,
+        Encoding::Zlib,
+        Encoding::Hextile,
+        Encoding::CoRre,
+        Encoding::Rre,
+        Encoding::Cursor,
+        Encoding::PointerPos,
+        Encoding::ExtendedDesktopSize,
+        Encoding::ContinuousUpdates,
+        Encoding::Fence,
+    ];
+    if let Some(level) = compress_level {
+        encodings.push(Encoding::CompressLevel(level.min(9)));
+    }
+    if let Some(level) = quality_level {
+        encodings.push(Encoding::QualityLevel(level.min(9)));
+    }
+
+    let mut vnc = match connect(&mut target, !exclusive, &tls, auth, record.as_deref()) {
         Ok(vnc) => vnc,
         Err(error) => {
-            error!("cannot initialize VNC session: {}", error);
+            error!("{:#}", error);
             std::process::exit(1)
         }
     };
-
-    let (width, height) = vnc.size();
+    let (mut width, mut height) = vnc.size();
     info!(
         "connected to \"{}\", {}x{} framebuffer",
         vnc.name(),
@@ -163,19 +386,11 @@ fn main() -> Result<(), Error> {
     let vnc_format = vnc.format();
     info!("received {:?}", vnc_format);
 
-    vnc.set_encodings(&[Encoding::CopyRect, Encoding::Zrle])
-        .unwrap();
-
-    vnc.request_update(
-        Rect {
-            left: 0,
-            top: 0,
-            width,
-            height,
-        },
-        false,
-    )
-    .unwrap();
+    if let Err(error) = start_session(&mut vnc, gray8, &encodings) {
+        error!("cannot start the VNC session: {}", error);
+        std::process::exit(1)
+    }
+    let gray = GrayConverter::new(vnc.format(), luma);
 
     #[cfg(feature = "eink_device")]
     debug!(
@@ -206,250 +421,132 @@ fn main() -> Result<(), Error> {
         fb.set_rotation(startup_rotation).ok();
     }
 
-    let post_proc_bin = PostProcBin {
-        data: (0..=255)
-            .map(|i| {
-                if contrast_exp == 1.0 {
-                    i
-                } else {
-                    let gray = contrast_gray_point;
-
-                    let rem_gray = 255.0 - gray;
-                    let inv_exponent = 1.0 / contrast_exp;
-
-                    let raw_color = i as f32;
-                    if raw_color < gray {
-                        (gray * (raw_color / gray).powf(contrast_exp)) as u8
-                    } else if raw_color > gray {
-                        (gray + rem_gray * ((raw_color - gray) / rem_gray).powf(inv_exponent)) as u8
-                    } else {
-                        gray as u8
-                    }
-                }
-            })
-            .map(|i| -> u8 {
-                if i > white_cutoff {
-                    255
-                } else {
-                    i
-                }
-            })
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap(),
-    };
-
     const FRAME_MS: u64 = 1000 / 30;
 
-    const MAX_DIRTY_REFRESHES: usize = 500;
-
-    let mut dirty_rects: Vec<Rectangle> = Vec::new();
-    let mut dirty_rects_since_refresh: Vec<Rectangle> = Vec::new();
-    let mut has_drawn_once = false;
-    let mut dirty_update_count = 0;
-
-    let mut time_at_last_draw = Instant::now();
-
-    let fb_rect = rect![0, 0, width as i32, height as i32];
+    let mut renderer = Renderer::new(fb, gray, contrast_curve, (width, height));
 
-    let post_proc_enabled = contrast_exp != 1.0;
+    let panel_size = panel_size(rotate);
+    let mut desktop_size_requested = false;
+    // Whether the server sent the cursor's shape and where it is, this
+    // session.
+    let mut cursor_shape = false;
+    let mut pointer_position = false;
+    let mut continuous_updates_requested = false;
 
     'running: loop {
         let time_at_sol = Instant::now();
 
-        for event in vnc.poll_iter() {
+        while let Some(event) = vnc.poll_event() {
             use client::Event;
 
             match event {
-                Event::Disconnected(None) => break 'running,
-                Event::Disconnected(Some(error)) => {
-                    error!("server disconnected: {:?}", error);
-                    break 'running;
-                }
-                Event::PutPixels(vnc_rect, ref pixels) => {
-                    debug!("Put pixels");
-
-                    let elapsed_ms = time_at_sol.elapsed().as_millis();
-                    debug!("network Δt: {}", elapsed_ms);
-
-                    let scale_down = 
-                        pixels
-                            .iter()
-                            .step_by(4)
-                            .map(|&c| post_proc_bin.data[c as usize])
-                            .collect();
-
-                    let post_proc_pixels = if post_proc_enabled {
-                        pixels
-                            .iter()
-                            .step_by(4)
-                            .map(|&c| post_proc_bin.data[c as usize])
-                            .collect()
-                    } else {
-                        Vec::new()
-                    };
-
-                    let pixels = if post_proc_enabled {
-                        &post_proc_pixels
-                    } else {
-                        &scale_down
-                    };
-
-                    let w = vnc_rect.width as u32;
-                    let h = vnc_rect.height as u32;
-                    let l = vnc_rect.left as u32;
-                    let t = vnc_rect.top as u32;
-
-                    let pixmap = ReadonlyPixmap {
-                        width: w as u32,
-                        height: h as u32,
-                        data: pixels,
-                    };
-                    debug!("Put pixels {} {} {} size {}",w,h,w*h,pixels.len());
-
-                    let elapsed_ms = time_at_sol.elapsed().as_millis();
-                    debug!("postproc Δt: {}", elapsed_ms);
-
-                    #[cfg(feature = "eink_device")]
-                    {
-                        for y in 0..pixmap.height {
-                            for x in 0..pixmap.width {
-                                let px = x + l;
-                                let py = y + t;
-                                let color = pixmap.get_pixel(x, y);
-                                fb.set_pixel(px, py, color);
-                            }
-                        }
+                Event::Disconnected(error) => {
+                    match error {
+                        None => info!("server disconnected"),
+                        Some(error) => error!("server disconnected: {:?}", error),
                     }
-
-                    let elapsed_ms = time_at_sol.elapsed().as_millis();
-                    debug!("draw Δt: {}", elapsed_ms);
-
-                    let w = vnc_rect.width as i32;
-                    let h = vnc_rect.height as i32;
-                    let l = vnc_rect.left as i32;
-                    let t = vnc_rect.top as i32;
-
-                    let delta_rect = rect![l, t, l + w, t + h];
-                    if delta_rect == fb_rect {
-                        dirty_rects.clear();
-                        dirty_rects_since_refresh.clear();
-                        #[cfg(feature = "eink_device")]
-                        {
-                            if !has_drawn_once || dirty_update_count > MAX_DIRTY_REFRESHES {
-                                fb.update(&fb_rect, UpdateMode::Full).ok();
-                                dirty_update_count = 0;
-                                has_drawn_once = true;
-                            } else {
-                                fb.update(&fb_rect, UpdateMode::Partial).ok();
-                            }
-                        }
-                    } else {
-                        push_to_dirty_rect_list(&mut dirty_rects, delta_rect);
+                    if !auto_reconnect {
+                        break 'running;
                     }
 
-                    let elapsed_ms = time_at_sol.elapsed().as_millis();
-                    debug!("rects Δt: {}", elapsed_ms);
-                }
-                Event::CopyPixels { src, dst } => {
-                    debug!("Copy pixels!");
-
+                    // Whatever was on screen stays there in the meantime.
                     #[cfg(feature = "eink_device")]
                     {
-                        let src_left = src.left as u32;
-                        let src_top = src.top as u32;
-
-                        let dst_left = dst.left as u32;
-                        let dst_top = dst.top as u32;
-
-                        let mut intermediary_pixmap =
-                            Pixmap::new(dst.width as u32, dst.height as u32);
-
-                        for y in 0..intermediary_pixmap.height {
-                            for x in 0..intermediary_pixmap.width {
-                                let color = fb.get_pixel(src_left + x, src_top + y);
-                                intermediary_pixmap.set_pixel(x, y, color);
-                            }
-                        }
-
-                        for y in 0..intermediary_pixmap.height {
-                            for x in 0..intermediary_pixmap.width {
-                                let color = intermediary_pixmap.get_pixel(x, y);
-                                fb.set_pixel(dst_left + x, dst_top + y, color);
-                            }
-                        }
+                        let visible_rect = renderer.visible_rect();
+                        draw_reconnecting_indicator(renderer.fb_mut(), &visible_rect);
                     }
+                    session += 1;
+                    let record = record.as_deref().map(|path| recording_path(path, session));
+                    vnc = reconnect(
+                        &mut target,
+                        !exclusive,
+                        &tls,
+                        &auth,
+                        record.as_deref(),
+                        gray8,
+                        &encodings,
+                    );
+                    info!("reconnected to \"{}\"", vnc.name());
 
-                    let delta_rect = rect![
-                        dst.left as i32,
-                        dst.top as i32,
-                        (dst.left + dst.width) as i32,
-                        (dst.top + dst.height) as i32
-                    ];
-                    push_to_dirty_rect_list(&mut dirty_rects, delta_rect);
+                    (width, height) = vnc.size();
+                    renderer.restart(GrayConverter::new(vnc.format(), luma), (width, height));
+                    desktop_size_requested = false;
+                    cursor_shape = false;
+                    pointer_position = false;
+                    continuous_updates_requested = false;
                 }
-                Event::EndOfFrame => {
-                    debug!("End of frame!");
-
-                    if !has_drawn_once {
-                        has_drawn_once = dirty_rects.len() > 0;
+                Event::Resize(new_width, new_height)
+                    if (new_width, new_height) != (width, height) =>
+                {
+                    (width, height) = (new_width, new_height);
+                    desktop_resized(&mut vnc, &mut renderer, width, height);
+                }
+                Event::DesktopLayout { size, screens } => {
+                    debug!("desktop layout {:?}", screens);
+                    if size != (width, height) {
+                        (width, height) = size;
+                        desktop_resized(&mut vnc, &mut renderer, width, height);
                     }
 
-                    dirty_update_count += 1;
-
-                    if dirty_update_count > MAX_DIRTY_REFRESHES {
-                        info!("Full refresh!");
-                        for dr in &dirty_rects_since_refresh {
-                            #[cfg(feature = "eink_device")]
-                            {
-                                fb.update(&dr, UpdateMode::Full).ok();
-                            }
-                        }
-                        dirty_update_count = 0;
-                        dirty_rects_since_refresh.clear();
-                    } else {
-                        for dr in &dirty_rects {
-                            debug!("Updating dirty rect {:?}", dr);
-
-                            #[cfg(feature = "eink_device")]
-                            {
-                                if dr.height() < 100 && dr.width() < 100 {
-                                    debug!("Fast mono update!");
-                                    fb.update(&dr, UpdateMode::FastMono).ok();
-                                } else {
-                                    fb.update(&dr, UpdateMode::Partial).ok();
-                                }
-                            }
-
-                            push_to_dirty_rect_list(&mut dirty_rects_since_refresh, *dr);
-                        }
-
-                        time_at_last_draw = Instant::now();
+                    if resize_desktop && !desktop_size_requested && size != panel_size {
+                        info!(
+                            "requesting a {}x{} desktop to match the screen",
+                            panel_size.0, panel_size.1
+                        );
+                        check_write(vnc.set_desktop_size(panel_size.0, panel_size.1));
+                        desktop_size_requested = true;
                     }
-
-                    dirty_rects.clear();
                 }
-                // x => info!("{:?}", x), /* ignore unsupported events */
-                _ => (),
+                Event::DesktopSizeRejected(error) => {
+                    warn!("server rejected the desktop size: {:?}", error);
+                }
+                // Sent in reply to SetEncodings if the server supports
+                // continuous updates; from then on it pushes changes by
+                // itself and polling stops.
+                Event::EndOfContinuousUpdates if !continuous_updates_requested => {
+                    info!("enabling continuous updates");
+                    check_write(vnc.enable_continuous_updates(Rect {
+                        left: 0,
+                        top: 0,
+                        width,
+                        height,
+                    }));
+                    continuous_updates_requested = true;
+                }
+                Event::SetCursor { .. } | Event::CursorPosition(..) => {
+                    cursor_shape |= matches!(event, Event::SetCursor { .. });
+                    pointer_position |= matches!(event, Event::CursorPosition(..));
+                    renderer.draw(event, Instant::now());
+                }
+                // Some servers take Cursor but not PointerPos, and nothing
+                // here moves the pointer, so it would be drawn by no one.
+                // It goes back into the server's pixels instead.
+                Event::EndOfFrame if cursor_shape && !pointer_position => {
+                    info!("server doesn't send the pointer position, leaving the cursor to it");
+                    let encodings: Vec<Encoding> = encodings
+                        .iter()
+                        .copied()
+                        .filter(|encoding| *encoding != Encoding::Cursor)
+                        .collect();
+                    check_write(vnc.set_encodings(&encodings));
+                    cursor_shape = false;
+                    renderer.draw(Event::EndOfFrame, Instant::now());
+                }
+                event => renderer.draw(event, Instant::now()),
             }
         }
 
         if FRAME_MS > time_at_sol.elapsed().as_millis() as u64 {
-            if dirty_rects_since_refresh.len() > 0 && time_at_last_draw.elapsed().as_secs() > 3 {
-                for dr in &dirty_rects_since_refresh {
-                    #[cfg(feature = "eink_device")]
-                    {
-                        fb.update(&dr, UpdateMode::Full).ok();
-                    }
-                }
-                dirty_update_count = 0;
-                dirty_rects_since_refresh.clear();
-            }
+            renderer.idle(Instant::now());
 
             if FRAME_MS > time_at_sol.elapsed().as_millis() as u64 {
-                thread::sleep(Duration::from_millis(
-                    FRAME_MS - time_at_sol.elapsed().as_millis() as u64,
-                ));
+                let remaining =
+                    Duration::from_millis(FRAME_MS - time_at_sol.elapsed().as_millis() as u64);
+                if vnc.continuous_updates() {
+                    vnc.wait_event(remaining);
+                } else {
+                    thread::sleep(remaining);
+                }
             }
         } else {
             info!(
@@ -458,35 +555,627 @@ fn main() -> Result<(), Error> {
             );
         }
 
-        vnc.request_update(
-            Rect {
-                left: 0,
-                top: 0,
-                width,
-                height,
-            },
-            true,
-        )
-        .unwrap();
+        if !vnc.continuous_updates() {
+            check_write(vnc.request_update(
+                Rect {
+                    left: 0,
+                    top: 0,
+                    width,
+                    height,
+                },
+                true,
+            ));
+        }
+    }
+
+    // Also closes the pipe to a --via command, so it can wind down.
+    vnc.disconnect().ok();
+
+    Ok(())
+}
+
+// The weights and the contrast curve that turn server pixels into what
+// goes on the panel.
+fn gray_options(matches: &clap::ArgMatches) -> (LumaWeights, [u8; 256]) {
+    let contrast_exp = value_t!(matches.value_of("CONTRAST"), f32).unwrap_or(1.0);
+    let contrast_gray_point = value_t!(matches.value_of("GRAYPOINT"), f32).unwrap_or(224.0);
+    let white_cutoff = value_t!(matches.value_of("WHITECUTOFF"), u8).unwrap_or(255);
+    let luma = match matches.value_of("LUMA") {
+        Some("709") => LumaWeights::REC709,
+        _ => LumaWeights::REC601,
+    };
+    (
+        luma,
+        gray::contrast_curve(contrast_exp, contrast_gray_point, white_cutoff),
+    )
+}
+
+// What a server, or a recording, is allowed to make einkvnc allocate.
+fn set_limits(matches: &clap::ArgMatches) {
+    let defaults = vnc::Limits::default();
+    vnc::set_limits(vnc::Limits {
+        max_length: value_t!(matches.value_of("MAXLENGTH"), usize).unwrap_or(defaults.max_length),
+        max_pixels: value_t!(matches.value_of("MAXPIXELS"), usize).unwrap_or(defaults.max_pixels),
+    });
+}
+
+// The server's CA or known_hosts, for security types over TLS or RSA-AES.
+// The host and port get filled in once they're known.
+fn tls_config(matches: &clap::ArgMatches) -> TlsConfig {
+    TlsConfig {
+        ca_file: matches.value_of("TLSCA").map(PathBuf::from),
+        known_hosts: matches
+            .value_of("KNOWNHOSTS")
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".config/einkvnc/known_hosts"))
+            }),
+        host: String::new(),
+        port: None,
+    }
+}
+
+// VNC authentication only takes 8 bytes of password, padded with zeros.
+fn password_key(password: &str) -> [u8; 8] {
+    if password.len() > 8 {
+        warn!("VNC authentication only uses the first 8 bytes of the password");
+    }
+    let mut key = [0; 8];
+    for (i, byte) in password.bytes().enumerate() {
+        if i == 8 {
+            break;
+        }
+        key[i] = byte
+    }
+    key
+}
+
+// The first of the server's methods the options have what it takes for.
+fn auth_choice(
+    methods: &[client::AuthMethod],
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Option<client::AuthChoice> {
+    debug!("available authentication methods: {:?}", methods);
+    for method in methods {
+        match method {
+            client::AuthMethod::None => return Some(client::AuthChoice::None),
+            client::AuthMethod::Password => {
+                return password.map(|password| client::AuthChoice::Password(password_key(password)))
+            }
+            client::AuthMethod::Plain => {
+                if let Some(password) = password {
+                    return Some(client::AuthChoice::Plain(
+                        username.unwrap_or_default().to_owned(),
+                        password.to_owned(),
+                    ));
+                }
+            }
+            client::AuthMethod::AppleRemoteDesktop => {
+                if let (Some(username), Some(password)) = (username, password) {
+                    return Some(client::AuthChoice::AppleRemoteDesktop(
+                        username.to_owned(),
+                        password.to_owned(),
+                    ));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Whether an address is safe to accept viewers on without a password: the
+// loopback, or one on the USB link to the device, where nobody else is.
+fn is_private_link(address: IpAddr) -> bool {
+    if address.is_loopback() {
+        return true;
+    }
+    let interfaces = match nix::ifaddrs::getifaddrs() {
+        Ok(interfaces) => interfaces,
+        Err(error) => {
+            warn!("cannot list network interfaces: {}", error);
+            return false;
+        }
+    };
+    interfaces
+        .filter(|interface| {
+            let interface_address = interface.address.as_ref().and_then(|address| {
+                match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+                    (Some(address), _) => Some(IpAddr::from(Ipv4Addr::from(address.ip()))),
+                    (_, Some(address)) => Some(IpAddr::from(address.ip())),
+                    _ => None,
+                }
+            });
+            interface_address == Some(address)
+        })
+        .any(|interface| {
+            // USB network devices sit under a USB bus in sysfs.
+            fs::canonicalize(format!("/sys/class/net/{}/device", interface.interface_name))
+                .map(|path| path.to_string_lossy().contains("/usb"))
+                .unwrap_or(false)
+        })
+}
+
+// Relays the server to every einkvnc that connects, all over the same
+// server connection, with frames already the way the panel shows them.
+fn run_proxy(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let host = matches.value_of("HOST").unwrap();
+    let port = value_t!(matches.value_of("PORT"), u16).unwrap_or(5900);
+    let listen_port = value_t!(matches.value_of("LISTEN"), u16).unwrap_or(5901);
+    let username = matches.value_of("USERNAME").map(str::to_owned);
+    let password = read_password(matches)?;
+    let tls = TlsConfig {
+        host: host.to_owned(),
+        port: Some(port),
+        ..tls_config(matches)
+    };
+    let viewer_password = match matches.value_of("VIEWERPASSWDFILE") {
+        Some(path) => Some(read_passwd_file(path)?),
+        None => matches.value_of("VIEWERPASSWORD").map(String::from),
+    };
+    let viewer_password = viewer_password.as_deref().map(password_key);
+    let bind = match matches.value_of("BIND") {
+        Some(address) => address
+            .parse::<IpAddr>()
+            .with_context(|| format!("{} is not an IP address", address))?,
+        None if viewer_password.is_some() => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::from(Ipv4Addr::LOCALHOST),
+    };
+    if viewer_password.is_none() && !is_private_link(bind) {
+        anyhow::bail!(
+            "{} is neither a loopback nor a USB network address, give the proxy a --viewer-password",
+            bind
+        );
+    }
+    let record = matches.value_of("RECORD").map(PathBuf::from);
+    let (luma, curve) = gray_options(matches);
+    let transcode = Transcode {
+        luma,
+        curve,
+        dither: !matches.is_present("NODITHER"),
+    };
+
+    let listener = TcpListener::bind((bind, listen_port))
+        .with_context(|| format!("cannot listen on {}:{}", bind, listen_port))?;
+    info!("listening on {}:{}", bind, listen_port);
+    let mut proxy: Option<Arc<Proxy>> = None;
+    let mut session = 0;
+    for client_stream in listener.incoming() {
+        let client_stream = match client_stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                warn!("cannot accept a connection: {}", error);
+                continue;
+            }
+        };
+        let address = client_stream.peer_addr().ok();
+        info!("accepted connection from {:?}", address);
+        set_keepalive(&client_stream);
+        client_stream.set_nodelay(true).ok();
+
+        // The server connection outlives the viewers, until the server
+        // goes away and the next viewer to arrive needs a new one.
+        let viewer_proxy = match proxy {
+            Some(ref proxy) if !proxy.is_finished() => Arc::clone(proxy),
+            _ => {
+                let server_stream = match TcpStream::connect((host, port)) {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error!("cannot connect to {}:{}: {}", host, port, error);
+                        continue;
+                    }
+                };
+                let auth = |methods: &[client::AuthMethod]| {
+                    auth_choice(methods, username.as_deref(), password.as_deref())
+                };
+                let record = record.as_deref().map(|path| recording_path(path, session));
+                session += 1;
+                match Proxy::from_tcp_stream(
+                    server_stream,
+                    &tls,
+                    auth,
+                    viewer_password,
+                    Some(transcode.clone()),
+                    record.as_deref(),
+                ) {
+                    Ok(new_proxy) => {
+                        info!("connected to {}:{}", host, port);
+                        let new_proxy = Arc::new(new_proxy);
+                        proxy = Some(Arc::clone(&new_proxy));
+                        new_proxy
+                    }
+                    Err(error) => {
+                        error!("cannot connect to {}:{}: {}", host, port, error);
+                        continue;
+                    }
+                }
+            }
+        };
+        thread::spawn(move || match viewer_proxy.serve(client_stream) {
+            Ok(()) => info!("{:?} disconnected", address),
+            Err(error) => error!("{:?} disconnected: {}", address, error),
+        });
+    }
+    Ok(())
+}
+
+// Plays a recording through the decoders and the renderer as fast as it can,
+// going by the recorded times for the refreshes. Frames go to
+// frame-NNNNN.png and refreshes to updates.log, as milliseconds into the
+// recording, mode and rectangle.
+fn run_replay(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let path = Path::new(matches.value_of("FILE").unwrap());
+    let output = match matches.value_of("OUTPUT") {
+        Some(dir) => PathBuf::from(dir),
+        None => path.with_extension(""),
+    };
+    fs::create_dir_all(&output)
+        .with_context(|| format!("cannot create {}", output.display()))?;
+    let file = fs::File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let mut reader = fbs::Reader::new(io::BufReader::new(file))
+        .with_context(|| format!("{} is not an FBS recording", path.display()))?;
+    let server_init = reader.read_handshake()?;
+    let (mut width, mut height) = (
+        server_init.framebuffer_width,
+        server_init.framebuffer_height,
+    );
+    info!(
+        "replaying \"{}\", {}x{} framebuffer",
+        server_init.name, width, height
+    );
+
+    let format = server_init.pixel_format;
+    let (luma, contrast_curve) = gray_options(matches);
+    let fb = Box::new(Pixmap::new(width as u32, height as u32));
+    let mut renderer = Renderer::new(
+        fb,
+        GrayConverter::new(format, luma),
+        contrast_curve,
+        (width, height),
+    );
+    renderer.log_updates();
+    let mut log = io::BufWriter::new(fs::File::create(output.join("updates.log"))?);
+
+    let start = Instant::now();
+    let format = Arc::new(Mutex::new(format));
+    let mut pump = client::Pump::new(reader, format, (width, height), None);
+    let (tx_events, rx_events) = mpsc::channel();
+    let mut frame = 0;
+    while pump.next_message(&tx_events)? {
+        let now = start + Duration::from_millis(pump.get_ref().timestamp() as u64);
+        if let Some(at) = renderer.idle_refresh_at().filter(|at| *at <= now) {
+            renderer.idle(at);
+            write_updates(&mut log, &mut renderer, at - start)?;
+        }
+
+        for event in rx_events.try_iter() {
+            use client::Event;
+
+            match event {
+                // The pixmap stands in for the panel and keeps its size.
+                Event::Resize(new_width, new_height)
+                | Event::DesktopLayout {
+                    size: (new_width, new_height),
+                    ..
+                } if (new_width, new_height) != (width, height) => {
+                    (width, height) = (new_width, new_height);
+                    renderer.resize(width, height);
+                }
+                Event::Resize(..) | Event::DesktopLayout { .. } => (),
+                Event::EndOfFrame => {
+                    renderer.draw(Event::EndOfFrame, now);
+                    let name = output.join(format!("frame-{:05}.png", frame));
+                    renderer.fb().save(&name.to_string_lossy())?;
+                    frame += 1;
+                }
+                event => renderer.draw(event, now),
+            }
+        }
+        write_updates(&mut log, &mut renderer, now - start)?;
     }
+    // What would have been refreshed once the screen went still.
+    if let Some(at) = renderer.idle_refresh_at() {
+        renderer.idle(at);
+        write_updates(&mut log, &mut renderer, at - start)?;
+    }
+    log.flush()?;
+    info!("{} frames written to {}", frame, output.display());
+    Ok(())
+}
 
+fn write_updates<W: io::Write>(
+    log: &mut W,
+    renderer: &mut Renderer,
+    time: Duration,
+) -> Result<(), Error> {
+    for (rect, mode) in renderer.take_updates() {
+        writeln!(
+            log,
+            "{} {:?} {},{} {}x{}",
+            time.as_millis(),
+            mode,
+            rect.min.x,
+            rect.min.y,
+            rect.width(),
+            rect.height()
+        )?;
+    }
     Ok(())
 }
 
-fn push_to_dirty_rect_list(list: &mut Vec<Rectangle>, rect: Rectangle) {
-    for dr in list.iter_mut() {
-        if dr.contains(&rect) {
-            return;
+// The password from wherever the options say, as --password is visible to
+// anyone running ps.
+fn read_password(matches: &clap::ArgMatches) -> Result<Option<String>, Error> {
+    if let Some(path) = matches.value_of("PASSWDFILE") {
+        return read_passwd_file(path).map(Some);
+    }
+    if let Some(name) = matches.value_of("PASSWORDENV") {
+        return env::var(name)
+            .map(Some)
+            .with_context(|| format!("cannot read the password from ${}", name));
+    }
+    if matches.is_present("PASSWORDSTDIN") {
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .context("cannot read the password from standard input")?;
+        let password = line.strip_suffix('\n').unwrap_or(&line);
+        return Ok(Some(password.strip_suffix('\r').unwrap_or(password).to_owned()));
+    }
+    Ok(matches.value_of("PASSWORD").map(String::from))
+}
+
+// A password file made by vncpasswd.
+fn read_passwd_file(path: &str) -> Result<String, Error> {
+    let data = fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    let password = security::decrypt_passwd(&data)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a VNC password file", path))?;
+    String::from_utf8(password).with_context(|| format!("the password in {} is not UTF-8", path))
+}
+
+// Whatever was on the panel is cleared, and all of the desktop asked for
+// again in its new size rather than only what changes from now on.
+fn desktop_resized(vnc: &mut Client, renderer: &mut Renderer, width: u16, height: u16) {
+    info!("server resized the desktop to {}x{}", width, height);
+    renderer.resize(width, height);
+    let rect = Rect {
+        left: 0,
+        top: 0,
+        width,
+        height,
+    };
+    if vnc.continuous_updates() {
+        check_write(vnc.enable_continuous_updates(rect));
+    }
+    check_write(vnc.request_update(rect, false));
+}
+
+// A failed write means the connection is gone, which the event thread
+// reports soon enough with a Disconnected event.
+fn check_write(result: vnc::Result<()>) {
+    if let Err(error) = result {
+        warn!("cannot write to the server: {}", error);
+    }
+}
+
+// Where the server is, or how to reach it. The command's process lasts as
+// long as the session through it, and the listener as long as we run, so
+// the server isn't refused between sessions.
+enum Target<'a> {
+    Tcp(&'a str, u16),
+    Unix(&'a str),
+    Via(&'a str, Option<Child>),
+    Listen(u16, Option<TcpListener>),
+}
+
+impl Target<'_> {
+    // Ends the command of a session that's over rather than leave it behind.
+    fn hang_up(&mut self) {
+        if let Target::Via(_, ref mut tunnel) = *self {
+            if let Some(mut child) = tunnel.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
         }
-        if rect.contains(&dr) {
-            *dr = rect;
-            return;
+    }
+}
+
+fn connect<Auth>(
+    target: &mut Target,
+    shared: bool,
+    tls: &TlsConfig,
+    auth: Auth,
+    record: Option<&Path>,
+) -> Result<Client, Error>
+where
+    Auth: FnOnce(&[client::AuthMethod]) -> Option<client::AuthChoice>,
+{
+    // Certificates get checked against, and pinned under, whatever names
+    // the server here.
+    let tls_for = |host: String, port: Option<u16>| TlsConfig {
+        host,
+        port,
+        ..tls.clone()
+    };
+    let session = match *target {
+        Target::Tcp(host, port) => {
+            info!("connecting to {}:{}", host, port);
+            let stream = TcpStream::connect((host, port))
+                .with_context(|| format!("cannot connect to {}:{}", host, port))?;
+            set_keepalive(&stream);
+            let config = tls_for(host.to_owned(), Some(port));
+            Client::from_tcp_stream(stream, shared, &config, auth, record)
         }
-        if rect.extends(&dr) {
-            dr.absorb(&rect);
-            return;
+        Target::Unix(path) => {
+            info!("connecting to {}", path);
+            let stream =
+                UnixStream::connect(path).with_context(|| format!("cannot connect to {}", path))?;
+            let config = tls_for(path.to_owned(), None);
+            Client::from_unix_stream(stream, shared, &config, auth, record)
+        }
+        Target::Via(command, ref mut tunnel) => {
+            info!("connecting through \"{}\"", command);
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("cannot run \"{}\"", command))?;
+            let (stdout, stdin) = (child.stdout.take().unwrap(), child.stdin.take().unwrap());
+            *tunnel = Some(child);
+            Client::new(
+                stdout,
+                stdin,
+                shared,
+                &tls_for(command.to_owned(), None),
+                auth,
+                record,
+            )
+        }
+        Target::Listen(port, ref mut listener) => {
+            if listener.is_none() {
+                info!("listening on port {}", port);
+                let bound = TcpListener::bind(("0.0.0.0", port))
+                    .with_context(|| format!("cannot listen on port {}", port))?;
+                *listener = Some(bound);
+            }
+            // One connection at a time; the server decides when the screen
+            // gets pushed to us.
+            let (stream, address) = listener
+                .as_ref()
+                .unwrap()
+                .accept()
+                .with_context(|| format!("cannot accept a connection on port {}", port))?;
+            info!("accepted connection from {}", address);
+            set_keepalive(&stream);
+            Client::from_tcp_stream(
+                stream,
+                shared,
+                &tls_for(address.ip().to_string(), None),
+                auth,
+                record,
+            )
+        }
+    };
+    session.context("cannot initialize VNC session")
+}
+
+// Every connection after the first is recorded next to the first one, with
+// its number added to the name.
+fn recording_path(path: &Path, session: usize) -> PathBuf {
+    if session == 0 {
+        return path.to_owned();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, session, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, session),
+    };
+    path.with_file_name(name)
+}
+
+// A server that goes away without closing the connection, e.g. when the PC
+// at the other end of USB networking suspends, would otherwise leave us
+// waiting forever.
+fn set_keepalive(stream: &TcpStream) {
+    use nix::sys::socket::{setsockopt, sockopt};
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    let result = setsockopt(fd, sockopt::KeepAlive, &true)
+        .and_then(|_| setsockopt(fd, sockopt::TcpKeepIdle, &10))
+        .and_then(|_| setsockopt(fd, sockopt::TcpKeepInterval, &5))
+        .and_then(|_| setsockopt(fd, sockopt::TcpKeepCount, &3));
+    if let Err(error) = result {
+        warn!("cannot enable TCP keepalive: {}", error);
+    }
+}
+
+// Negotiates what every new session needs, down to asking for the whole
+// desktop to start from.
+fn start_session(vnc: &mut Client, gray8: bool, encodings: &[Encoding]) -> vnc::Result<()> {
+    // Done before SetEncodings so that the update set_format() discards
+    // doesn't swallow any of the events announcing extensions.
+    if gray8 {
+        info!("requesting {:?}", gray::GRAY8_FORMAT);
+        vnc.set_format(gray::GRAY8_FORMAT)?;
+    }
+    vnc.set_encodings(encodings)?;
+
+    let (width, height) = vnc.size();
+    vnc.request_update(
+        Rect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        },
+        false,
+    )
+}
+
+// Retries with exponential backoff until a session is up again.
+fn reconnect<Auth>(
+    target: &mut Target,
+    shared: bool,
+    tls: &TlsConfig,
+    auth: &Auth,
+    record: Option<&Path>,
+    gray8: bool,
+    encodings: &[Encoding],
+) -> Client
+where
+    Auth: Fn(&[client::AuthMethod]) -> Option<client::AuthChoice>,
+{
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        // Whatever the last session or attempt left running goes first.
+        target.hang_up();
+        thread::sleep(backoff);
+        let result = connect(target, shared, tls, auth, record).and_then(|mut vnc| {
+            start_session(&mut vnc, gray8, encodings)?;
+            Ok(vnc)
+        });
+        match result {
+            Ok(vnc) => return vnc,
+            Err(error) => {
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                warn!("{:#}, retrying in {}s", error, backoff.as_secs());
+            }
         }
     }
+}
 
-    list.push(rect);
+// A small hollow square in the bottom right corner of the desktop while
+// the connection is down. The full update after reconnecting paints over
+// it.
+#[cfg(feature = "eink_device")]
+fn draw_reconnecting_indicator(fb: &mut dyn Framebuffer, fb_rect: &Rectangle) {
+    const SIZE: i32 = 32;
+    const BORDER: i32 = 6;
+
+    let max = fb_rect.max;
+    let outer = rect![max.x - 2 * SIZE, max.y - 2 * SIZE, max.x - SIZE, max.y - SIZE];
+    let inner = rect![
+        outer.min.x + BORDER,
+        outer.min.y + BORDER,
+        outer.max.x - BORDER,
+        outer.max.y - BORDER
+    ];
+    fb.draw_rectangle(&outer, color::BLACK);
+    fb.draw_rectangle(&inner, color::WHITE);
+    fb.update(&outer, UpdateMode::Fast).ok();
+}
+
+// The size of the panel in the orientation given by the rotation.
+fn panel_size(rotation: i8) -> (u16, u16) {
+    let (width, height) = CURRENT_DEVICE.dims;
+    match CURRENT_DEVICE.orientation(rotation) {
+        Orientation::Portrait => (width as u16, height as u16),
+        Orientation::Landscape => (height as u16, width as u16),
+    }
 }
@@ -0,0 +1,424 @@
+use crate::color::WHITE;
+use crate::cursor::Cursor;
+use crate::framebuffer::{Framebuffer, UpdateMode};
+use crate::geom::Rectangle;
+use crate::vnc::client::Event;
+use crate::vnc::GrayConverter;
+use std::time::{Duration, Instant};
+
+const MAX_DIRTY_REFRESHES: usize = 500;
+
+// How long the screen has to stay still before what was drawn since the
+// last full refresh gets one.
+const IDLE_REFRESH: Duration = Duration::from_secs(4);
+
+#[repr(align(256))]
+pub struct PostProcBin {
+    data: [u8; 256],
+}
+
+// Draws the events of a session on a framebuffer and decides how each part
+// of the panel gets refreshed, whether the events come from a server or a
+// recording and the framebuffer is the panel or a pixmap.
+pub struct Renderer {
+    fb: Box<dyn Framebuffer>,
+    gray: GrayConverter,
+    post_proc_bin: PostProcBin,
+    fb_rect: Rectangle,
+    dirty_rects: Vec<Rectangle>,
+    dirty_rects_since_refresh: Vec<Rectangle>,
+    has_drawn_once: bool,
+    // The desktop changed size since the last frame, so the next one
+    // refreshes the whole panel.
+    refresh_all: bool,
+    dirty_update_count: usize,
+    time_at_last_draw: Instant,
+    // Areas the cursor left or entered since the last frame.
+    cursor: Cursor,
+    cursor_rects: Vec<Rectangle>,
+    // Every refresh asked of the framebuffer, if they're being logged.
+    updates: Option<Vec<(Rectangle, UpdateMode)>>,
+}
+
+impl Renderer {
+    pub fn new(
+        fb: Box<dyn Framebuffer>,
+        gray: GrayConverter,
+        contrast_curve: [u8; 256],
+        size: (u16, u16),
+    ) -> Renderer {
+        Renderer {
+            fb,
+            gray,
+            post_proc_bin: PostProcBin {
+                data: contrast_curve,
+            },
+            fb_rect: rect![0, 0, size.0 as i32, size.1 as i32],
+            dirty_rects: Vec::new(),
+            dirty_rects_since_refresh: Vec::new(),
+            has_drawn_once: false,
+            refresh_all: false,
+            dirty_update_count: 0,
+            time_at_last_draw: Instant::now(),
+            cursor: Cursor::new(),
+            cursor_rects: Vec::new(),
+            updates: None,
+        }
+    }
+
+    pub fn fb_mut(&mut self) -> &mut dyn Framebuffer {
+        self.fb.as_mut()
+    }
+
+    pub fn fb(&self) -> &dyn Framebuffer {
+        self.fb.as_ref()
+    }
+
+    // The part of the desktop that fits on the panel, which is all that's
+    // ever drawn.
+    pub fn visible_rect(&self) -> Rectangle {
+        self.fb_rect
+            .intersection(&self.fb.rect())
+            .unwrap_or_default()
+    }
+
+    // Keeps every refresh from now on, for `take_updates`.
+    pub fn log_updates(&mut self) {
+        self.updates = Some(Vec::new());
+    }
+
+    pub fn take_updates(&mut self) -> Vec<(Rectangle, UpdateMode)> {
+        self.updates
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn update(&mut self, rect: &Rectangle, mode: UpdateMode) {
+        let rect = match rect.intersection(&self.fb.rect()) {
+            Some(rect) => rect,
+            None => return,
+        };
+        self.fb.update(&rect, mode).ok();
+        if let Some(ref mut updates) = self.updates {
+            updates.push((rect, mode));
+        }
+    }
+
+    // A new session, on top of whatever the last one left on the panel.
+    pub fn restart(&mut self, gray: GrayConverter, size: (u16, u16)) {
+        self.gray = gray;
+        self.fb_rect = rect![0, 0, size.0 as i32, size.1 as i32];
+        self.cursor = Cursor::new();
+        self.cursor_rects.clear();
+        self.dirty_rects.clear();
+        // Clean the panel of the indicator and any ghosting with the first
+        // full frame.
+        self.has_drawn_once = false;
+    }
+
+    // Starts over on a blank panel, so that nothing of the old desktop is
+    // left around the new one.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.fb_rect = rect![0, 0, width as i32, height as i32];
+        self.cursor.hide(self.fb.as_mut());
+        self.cursor_rects.clear();
+        self.fb.clear(WHITE);
+        self.dirty_rects.clear();
+        self.dirty_rects_since_refresh.clear();
+        self.refresh_all = true;
+    }
+
+    // Handles the events that change what's on screen and ignores the rest.
+    pub fn draw(&mut self, event: Event, now: Instant) {
+        match event {
+            Event::SetColourMap {
+                first_colour,
+                colours,
+            } => {
+                debug!(
+                    "colour map: {} entries from {}",
+                    colours.len(),
+                    first_colour
+                );
+                self.gray.set_colour_map(first_colour, &colours);
+            }
+            Event::PutPixels(vnc_rect, ref pixels) => {
+                debug!("Put pixels");
+                let start = Instant::now();
+
+                let mut gray_pixels = self.gray.convert(pixels);
+                for c in gray_pixels.iter_mut() {
+                    *c = self.post_proc_bin.data[*c as usize];
+                }
+                let elapsed_ms = start.elapsed().as_millis();
+                debug!("postproc Δt: {}", elapsed_ms);
+
+                let update_rect = rect![
+                    vnc_rect.left as i32,
+                    vnc_rect.top as i32,
+                    (vnc_rect.left + vnc_rect.width) as i32,
+                    (vnc_rect.top + vnc_rect.height) as i32
+                ];
+                self.cursor_rects
+                    .extend(self.cursor.hide_within(self.fb.as_mut(), &update_rect));
+                self.fb
+                    .blit_gray(&update_rect, &gray_pixels, vnc_rect.width as usize);
+
+                let elapsed_ms = start.elapsed().as_millis();
+                debug!("draw Δt: {}", elapsed_ms);
+
+                if update_rect == self.fb_rect && !self.refresh_all {
+                    self.dirty_rects.clear();
+                    self.dirty_rects_since_refresh.clear();
+                    let fb_rect = self.fb_rect;
+                    if !self.has_drawn_once || self.dirty_update_count > MAX_DIRTY_REFRESHES {
+                        self.update(&fb_rect, UpdateMode::Full);
+                        self.dirty_update_count = 0;
+                        self.has_drawn_once = true;
+                    } else {
+                        self.update(&fb_rect, UpdateMode::Partial);
+                    }
+                } else {
+                    push_to_dirty_rect_list(&mut self.dirty_rects, update_rect);
+                }
+
+                let elapsed_ms = start.elapsed().as_millis();
+                debug!("rects Δt: {}", elapsed_ms);
+            }
+            Event::FillPixels(vnc_rect, ref pixel) => {
+                debug!("Fill pixels");
+
+                let delta_rect = rect![
+                    vnc_rect.left as i32,
+                    vnc_rect.top as i32,
+                    (vnc_rect.left + vnc_rect.width) as i32,
+                    (vnc_rect.top + vnc_rect.height) as i32
+                ];
+
+                self.cursor_rects
+                    .extend(self.cursor.hide_within(self.fb.as_mut(), &delta_rect));
+                let color = self.post_proc_bin.data[self.gray.pixel(pixel) as usize];
+                self.fb.draw_rectangle(&delta_rect, color);
+
+                push_to_dirty_rect_list(&mut self.dirty_rects, delta_rect);
+            }
+            Event::CopyPixels { src, dst } => {
+                debug!("Copy pixels!");
+
+                let src_rect = rect![
+                    src.left as i32,
+                    src.top as i32,
+                    (src.left + src.width) as i32,
+                    (src.top + src.height) as i32
+                ];
+                let delta_rect = rect![
+                    dst.left as i32,
+                    dst.top as i32,
+                    (dst.left + dst.width) as i32,
+                    (dst.top + dst.height) as i32
+                ];
+                for rect in [&src_rect, &delta_rect] {
+                    self.cursor_rects
+                        .extend(self.cursor.hide_within(self.fb.as_mut(), rect));
+                }
+
+                self.fb.copy_region(&src_rect, delta_rect.min);
+
+                push_to_dirty_rect_list(&mut self.dirty_rects, delta_rect);
+            }
+            Event::SetCursor {
+                size,
+                hotspot,
+                pixels,
+                mask_bits,
+            } => {
+                debug!("cursor shape {}x{}", size.0, size.1);
+                let pixels = self
+                    .gray
+                    .convert(&pixels)
+                    .into_iter()
+                    .map(|c| self.post_proc_bin.data[c as usize])
+                    .collect();
+                self.cursor_rects.extend(self.cursor.hide(self.fb.as_mut()));
+                self.cursor.set_shape(size, hotspot, pixels, mask_bits);
+            }
+            Event::CursorPosition(x, y) => {
+                self.cursor_rects.extend(self.cursor.hide(self.fb.as_mut()));
+                self.cursor.move_to(x, y);
+            }
+            Event::EndOfFrame => {
+                debug!("End of frame!");
+
+                if !self.has_drawn_once {
+                    self.has_drawn_once = !self.dirty_rects.is_empty();
+                }
+
+                self.dirty_update_count += 1;
+
+                if self.refresh_all && !self.dirty_rects.is_empty() {
+                    info!("Full refresh after a resize");
+                    let panel_rect = self.fb.rect();
+                    self.update(&panel_rect, UpdateMode::Full);
+                    self.refresh_all = false;
+                    self.dirty_update_count = 0;
+                    self.time_at_last_draw = now;
+                } else if self.dirty_update_count > MAX_DIRTY_REFRESHES {
+                    info!("Full refresh!");
+                    for dr in std::mem::take(&mut self.dirty_rects_since_refresh) {
+                        self.update(&dr, UpdateMode::Full);
+                    }
+                    self.dirty_update_count = 0;
+                } else {
+                    for dr in std::mem::take(&mut self.dirty_rects) {
+                        debug!("Updating dirty rect {:?}", dr);
+
+                        if dr.height() < 100 && dr.width() < 100 {
+                            debug!("Fast mono update!");
+                            self.update(&dr, UpdateMode::FastMono);
+                        } else {
+                            self.update(&dr, UpdateMode::Partial);
+                        }
+
+                        push_to_dirty_rect_list(&mut self.dirty_rects_since_refresh, dr);
+                    }
+
+                    self.time_at_last_draw = now;
+                }
+
+                // The cursor gets its own refresh, so that pointing around
+                // leaves the rest of the screen alone.
+                let visible_rect = self.visible_rect();
+                self.cursor_rects
+                    .extend(self.cursor.show(self.fb.as_mut(), &visible_rect));
+                for cr in std::mem::take(&mut self.cursor_rects) {
+                    self.update(&cr, UpdateMode::FastMono);
+                    push_to_dirty_rect_list(&mut self.dirty_rects_since_refresh, cr);
+                }
+
+                self.dirty_rects.clear();
+            }
+            _ => (),
+        }
+    }
+
+    // When what was drawn since the last full refresh gets one, if anything
+    // was.
+    pub fn idle_refresh_at(&self) -> Option<Instant> {
+        if self.dirty_rects_since_refresh.is_empty() {
+            None
+        } else {
+            Some(self.time_at_last_draw + IDLE_REFRESH)
+        }
+    }
+
+    pub fn idle(&mut self, now: Instant) {
+        if self.idle_refresh_at().is_some_and(|at| now >= at) {
+            for dr in std::mem::take(&mut self.dirty_rects_since_refresh) {
+                self.update(&dr, UpdateMode::Full);
+            }
+            self.dirty_update_count = 0;
+        }
+    }
+}
+
+fn push_to_dirty_rect_list(list: &mut Vec<Rectangle>, rect: Rectangle) {
+    for dr in list.iter_mut() {
+        if dr.contains(&rect) {
+            return;
+        }
+        if rect.contains(dr) {
+            *dr = rect;
+            return;
+        }
+        if rect.extends(dr) {
+            dr.absorb(&rect);
+            return;
+        }
+    }
+
+    list.push(rect);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Renderer;
+    use crate::framebuffer::{Pixmap, UpdateMode};
+    use crate::vnc::client::Event;
+    use crate::vnc::gray::{contrast_curve, GRAY8_FORMAT};
+    use crate::vnc::{GrayConverter, LumaWeights, Rect};
+    use std::time::Instant;
+
+    fn put(left: u16, width: u16, color: u8) -> Event {
+        let rect = Rect {
+            left,
+            top: 0,
+            width,
+            height: 2,
+        };
+        Event::PutPixels(rect, vec![color; width as usize * 2])
+    }
+
+    #[test]
+    fn test_desktop_larger_than_panel() {
+        let gray = GrayConverter::new(GRAY8_FORMAT, LumaWeights::REC601);
+        let fb = Box::new(Pixmap::new(4, 2));
+        let mut renderer = Renderer::new(fb, gray, contrast_curve(1.0, 224.0, 255), (4, 2));
+        renderer.log_updates();
+        let now = Instant::now();
+        renderer.resize(4, 4);
+        renderer.draw(
+            Event::SetCursor {
+                size: (1, 1),
+                hotspot: (0, 0),
+                pixels: vec![0],
+                mask_bits: vec![0x80],
+            },
+            now,
+        );
+        renderer.draw(Event::CursorPosition(0, 3), now);
+        let rect = Rect {
+            left: 0,
+            top: 1,
+            width: 4,
+            height: 3,
+        };
+        renderer.draw(Event::FillPixels(rect, vec![0]), now);
+        renderer.draw(Event::EndOfFrame, now);
+        assert_eq!(renderer.fb().get_pixel(0, 1), 0);
+        assert_eq!(
+            renderer.take_updates(),
+            vec![(rect![0, 0, 4, 2], UpdateMode::Full)]
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let gray = GrayConverter::new(GRAY8_FORMAT, LumaWeights::REC601);
+        let fb = Box::new(Pixmap::new(4, 2));
+        let mut renderer = Renderer::new(fb, gray, contrast_curve(1.0, 224.0, 255), (4, 2));
+        renderer.log_updates();
+        let now = Instant::now();
+        renderer.draw(put(0, 4, 0), now);
+        renderer.draw(Event::EndOfFrame, now);
+        renderer.take_updates();
+
+        // Narrower, and drawn in pieces.
+        renderer.resize(3, 2);
+        assert_eq!(renderer.fb().get_pixel(3, 0), 255);
+        renderer.draw(put(0, 1, 10), now);
+        renderer.draw(put(1, 2, 20), now);
+        renderer.draw(Event::EndOfFrame, now);
+        assert_eq!(
+            renderer.take_updates(),
+            vec![(rect![0, 0, 4, 2], UpdateMode::Full)]
+        );
+        // Back to refreshing what changes.
+        renderer.draw(put(0, 1, 30), now);
+        renderer.draw(Event::EndOfFrame, now);
+        assert_eq!(
+            renderer.take_updates(),
+            vec![(rect![0, 0, 1, 2], UpdateMode::FastMono)]
+        );
+    }
+}
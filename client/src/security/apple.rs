@@ -1,41 +1,107 @@
-use std::cmp::min;
-use octavo::crypto::asymmetric::dh::{DHParameters, DHPublicKey};
+use crate::security::md5::md5;
+use crate::vnc::protocol;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
 use num_bigint::BigUint;
-use crypto::aessafe::AesSafe128Encryptor;
-use crypto::symmetriccipher::BlockEncryptor;
-use ::protocol;
-use security::md5::md5;
+use rand::RngCore;
+use std::cmp::min;
 
 // http://cafbit.com/entry/apple_remote_desktop_quirks
-pub fn apple_auth(username: &str, password: &str,
-                  handshake: &protocol::AppleAuthHandshake) -> protocol::AppleAuthResponse {
-    let param = DHParameters::new(&handshake.prime, handshake.generator as u64);
-    let priv_key = param.private_key();
-    let pub_key = priv_key.public_key();
-    let secret =
-        md5(
-            &priv_key.exchange(&DHPublicKey::new(
-                BigUint::from_bytes_be(&handshake.peer_key)
-            )).to_bytes_be()
-        );
-
+pub fn apple_auth(
+    username: &str,
+    password: &str,
+    handshake: &protocol::AppleAuthHandshake,
+) -> protocol::AppleAuthResponse {
+    let mut rng = rand::thread_rng();
+    let mut private_key = vec![0; handshake.prime.len()];
+    rng.fill_bytes(&mut private_key);
+    // Whatever follows the terminating zero of each field is left random.
     let mut credentials = [0u8; 128];
-    let ul = min(64, username.len());
+    rng.fill_bytes(&mut credentials);
+    respond(
+        username,
+        password,
+        handshake,
+        &BigUint::from_bytes_be(&private_key),
+        credentials,
+    )
+}
+
+fn respond(
+    username: &str,
+    password: &str,
+    handshake: &protocol::AppleAuthHandshake,
+    private_key: &BigUint,
+    mut credentials: [u8; 128],
+) -> protocol::AppleAuthResponse {
+    let prime = BigUint::from_bytes_be(&handshake.prime);
+    let generator = BigUint::from(handshake.generator);
+    let peer_key = BigUint::from_bytes_be(&handshake.peer_key);
+    let pub_key = generator.modpow(private_key, &prime);
+    let shared = peer_key.modpow(private_key, &prime);
+    // Both keys go out at the full key length, leading zeros included.
+    let key_length = handshake.prime.len();
+    let secret = md5(&pad(&shared, key_length));
+
+    let ul = min(63, username.len());
     credentials[0..ul].copy_from_slice(&username.as_bytes()[0..ul]);
-    let pl = min(64, password.len());
+    credentials[ul] = 0;
+    let pl = min(63, password.len());
     credentials[64..(64 + pl)].copy_from_slice(&password.as_bytes()[0..pl]);
+    credentials[64 + pl] = 0;
 
-    let mut ciphertext = [0u8; 128];
-    let aes = AesSafe128Encryptor::new(&secret);
-    for i in 0..(credentials.len() / aes.block_size()) {
-        let start = i * aes.block_size();
-        let end = (i + 1) * aes.block_size();
-        let input = &credentials[start..end];
-        aes.encrypt_block(input, &mut ciphertext[start..end]);
+    let aes = Aes128::new(&GenericArray::from(secret));
+    for block in credentials.chunks_exact_mut(16) {
+        aes.encrypt_block(GenericArray::from_mut_slice(block));
     }
 
     protocol::AppleAuthResponse {
-        ciphertext: ciphertext,
-        pub_key: pub_key.key().to_bytes_be(),
+        ciphertext: credentials,
+        pub_key: pad(&pub_key, key_length),
+    }
+}
+
+fn pad(number: &BigUint, length: usize) -> Vec<u8> {
+    let bytes = number.to_bytes_be();
+    let mut padded = vec![0; length.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::respond;
+    use crate::vnc::protocol::AppleAuthHandshake;
+    use num_bigint::BigUint;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_respond() {
+        // Worked out independently; the shared secret happens to start with
+        // two zero bytes, which must survive into the MD5 input.
+        let handshake = AppleAuthHandshake {
+            generator: 5,
+            prime: hex("7fffffffffffffffffffffffffffffff"),
+            peer_key: hex("6d5ce87308ade3e9723222d537a9cdc9"),
+        };
+        let private_key = BigUint::from_bytes_be(&hex("0fedcba9876543210fedcba987658804"));
+        let response = respond("alice", "hunter2", &handshake, &private_key, [0; 128]);
+        assert_eq!(response.pub_key, hex("573b8a2501dd6f936a17ed165a82d73e"));
+        assert_eq!(
+            response.ciphertext.to_vec(),
+            hex(concat!(
+                "b8722393f3fa5fdc4ea85641fce7c2d4b6968276206918b8f54cb6d76fb9fc9c",
+                "b6968276206918b8f54cb6d76fb9fc9cb6968276206918b8f54cb6d76fb9fc9c",
+                "4034c178126154b2c61ed93f8538cb4bb6968276206918b8f54cb6d76fb9fc9c",
+                "b6968276206918b8f54cb6d76fb9fc9cb6968276206918b8f54cb6d76fb9fc9c",
+            ))
+        );
     }
 }
@@ -1,5 +1,7 @@
 pub mod des;
 pub use self::des::encrypt as des;
+mod passwd;
+pub use self::passwd::decrypt_passwd;
 
 #[cfg(feature = "apple-auth")]
 mod apple;
@@ -0,0 +1,32 @@
+use crate::security::des;
+
+// The fixed key every VNC implementation obfuscates stored passwords with,
+// with the bits of each byte already reversed for plain DES.
+const PASSWD_KEY: des::Key = [0xe8, 0x4a, 0xd6, 0x60, 0xc4, 0x72, 0x1a, 0xe0];
+
+// Recovers the password from the contents of a `vncpasswd` file. TigerVNC
+// may append a second, view-only password, which is ignored.
+pub fn decrypt_passwd(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 8 {
+        return None;
+    }
+    let mut password = des::decrypt(&data[..8], &PASSWD_KEY);
+    if let Some(end) = password.iter().position(|&byte| byte == 0) {
+        password.truncate(end);
+    }
+    Some(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decrypt_passwd;
+
+    #[test]
+    fn test_decrypt_passwd() {
+        let password = [0xdb, 0xd8, 0x3c, 0xfd, 0x72, 0x7a, 0x14, 0x58];
+        assert_eq!(decrypt_passwd(&password).unwrap(), b"password");
+        let short = [0x5d, 0xc4, 0x8a, 0xe6, 0x3f, 0xcd, 0xa4, 0xb4, 1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(decrypt_passwd(&short).unwrap(), b"kobo");
+        assert_eq!(decrypt_passwd(&[1, 2, 3]), None);
+    }
+}
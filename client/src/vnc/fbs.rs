@@ -0,0 +1,271 @@
+use crate::vnc::protocol::{self, Message};
+use crate::vnc::{Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+const MAGIC: &[u8; 12] = b"FBS 001.000\n";
+
+// Records what a server sends in the FBS format of rfbproxy: blocks of
+// bytes, each padded to 4 bytes and stamped with the milliseconds since the
+// recording started. The handshake is written as that of an RFB 3.3 server
+// without authentication, which is what players expect and the only way to
+// replay an encrypted session.
+pub struct Recorder {
+    file: BufWriter<File>,
+    server_init: protocol::ServerInit,
+    start: Instant,
+    pending: Vec<u8>,
+    pending_since: u32,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, server_init: &protocol::ServerInit) -> Result<Recorder> {
+        let mut recorder = Recorder {
+            file: BufWriter::new(File::create(path)?),
+            server_init: server_init.clone(),
+            start: Instant::now(),
+            pending: Vec::new(),
+            pending_since: 0,
+        };
+        recorder.start_over()?;
+        Ok(recorder)
+    }
+
+    fn start_over(&mut self) -> Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        self.start = Instant::now();
+
+        let mut handshake = Vec::new();
+        protocol::Version::Rfb33.write_to(&mut handshake)?;
+        handshake.write_u32::<BigEndian>(1)?;
+        self.server_init.write_to(&mut handshake)?;
+        self.file.write_all(MAGIC)?;
+        write_block(&mut self.file, &handshake, 0)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    // FBS has no way of saying the format changed, so the recording starts
+    // over in the new one. Clients only change it before the session proper.
+    fn set_format(&mut self, format: protocol::PixelFormat) -> Result<()> {
+        if format == self.server_init.pixel_format {
+            return Ok(());
+        }
+        self.server_init.pixel_format = format;
+        self.start_over()
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        if self.pending.is_empty() {
+            self.pending_since = self.start.elapsed().as_millis() as u32;
+        }
+        self.pending.extend_from_slice(bytes);
+    }
+
+    fn end_message(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        write_block(&mut self.file, &self.pending, self.pending_since)?;
+        self.file.flush()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+fn write_block<W: Write>(writer: &mut W, data: &[u8], timestamp: u32) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(data)?;
+    writer.write_all(&[0; 3][..(4 - data.len() % 4) % 4])?;
+    writer.write_u32::<BigEndian>(timestamp)
+}
+
+// The server's half of a connection, recorded as it's read if there's a
+// recorder, one block per message.
+pub struct Recording<R> {
+    reader: R,
+    recorder: Option<Recorder>,
+}
+
+impl<R: Read> Recording<R> {
+    pub fn new(reader: R, recorder: Option<Recorder>) -> Recording<R> {
+        Recording { reader, recorder }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn set_format(&mut self, format: protocol::PixelFormat) {
+        if let Some(Err(error)) = self.recorder.as_mut().map(|r| r.set_format(format)) {
+            warn!("recording stopped: {}", error);
+            self.recorder = None;
+        }
+    }
+
+    // A failing recording only stops the recording, not the session.
+    pub fn end_message(&mut self) {
+        if let Some(Err(error)) = self.recorder.as_mut().map(Recorder::end_message) {
+            warn!("recording stopped: {}", error);
+            self.recorder = None;
+        }
+    }
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.reader.read(buf)?;
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record(&buf[..length]);
+        }
+        Ok(length)
+    }
+}
+
+// Plays a recording back as the stream it was made of, keeping track of
+// when the bytes being read arrived.
+pub struct Reader<R> {
+    reader: R,
+    block: Vec<u8>,
+    position: usize,
+    timestamp: u32,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut reader: R) -> Result<Reader<R>> {
+        let mut magic = [0; 12];
+        reader.read_exact(&mut magic)?;
+        // Only the major version matters to the layout.
+        if magic[..8] != MAGIC[..8] {
+            return Err(Error::Unexpected("FBS header"));
+        }
+        Ok(Reader {
+            reader,
+            block: Vec::new(),
+            position: 0,
+            timestamp: 0,
+        })
+    }
+
+    // What comes before the messages, as recorded by rfbproxy or `Recorder`.
+    // Only RFB 3.3 handshakes are recorded that way.
+    pub fn read_handshake(&mut self) -> Result<protocol::ServerInit> {
+        if protocol::Version::read_from(self)? != protocol::Version::Rfb33 {
+            return Err(Error::Unexpected("recorded protocol version"));
+        }
+        match self.read_u32::<BigEndian>()? {
+            1 => (),
+            // The challenge and the result; the response came from the
+            // client and wasn't recorded.
+            2 => {
+                let mut challenge = [0; 16];
+                self.read_exact(&mut challenge)?;
+                if self.read_u32::<BigEndian>()? != 0 {
+                    return Err(Error::AuthenticationFailure(String::from(
+                        "recorded session failed authentication",
+                    )));
+                }
+            }
+            _ => return Err(Error::Unexpected("recorded security type")),
+        }
+        protocol::ServerInit::read_from(self)
+    }
+
+    // Milliseconds from the start of the recording to the block read from.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    // Returns false at the end of the recording.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let length = match self.reader.read_u32::<BigEndian>() {
+            Ok(length) => length as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(error) => return Err(error),
+        };
+        // Read as it comes rather than allocated up front, in case the file
+        // is cut short or not a recording after all.
+        let padded = length + (4 - length % 4) % 4;
+        self.block.clear();
+        (&mut self.reader)
+            .take(padded as u64)
+            .read_to_end(&mut self.block)?;
+        if self.block.len() < padded {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.block.truncate(length);
+        self.position = 0;
+        self.timestamp = self.reader.read_u32::<BigEndian>()?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let length = buf.len().min(self.block.len() - self.position);
+        buf[..length].copy_from_slice(&self.block[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reader, Recorder, Recording};
+    use crate::vnc::gray::GRAY8_FORMAT;
+    use crate::vnc::protocol;
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("einkvnc-{}.fbs", std::process::id()));
+        let server_init = protocol::ServerInit {
+            framebuffer_width: 2,
+            framebuffer_height: 1,
+            pixel_format: GRAY8_FORMAT,
+            name: String::from("test"),
+        };
+        let recorder = Recorder::create(&path, &server_init).unwrap();
+        let mut recording = Recording::new(&[2, 0, 0, 0, 0, 2][..], Some(recorder));
+        let mut message = [0; 5];
+        recording.read_exact(&mut message).unwrap();
+        recording.end_message();
+        recording.read_exact(&mut message[..1]).unwrap();
+        recording.end_message();
+        // Nothing read, nothing recorded.
+        recording.end_message();
+        drop(recording);
+
+        let mut reader = Reader::new(fs::File::open(&path).unwrap()).unwrap();
+        let handshake = reader.read_handshake().unwrap();
+        assert_eq!(handshake.name, "test");
+        assert_eq!(reader.timestamp(), 0);
+        let mut messages = Vec::new();
+        reader.read_to_end(&mut messages).unwrap();
+        assert_eq!(messages, vec![2, 0, 0, 0, 0, 2]);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&data[..12], b"FBS 001.000\n");
+        // The version, no authentication and a 28 byte ServerInit.
+        assert_eq!(&data[12..16], &[0, 0, 0, 44]);
+        assert_eq!(&data[16..32], b"RFB 003.003\n\0\0\0\x01");
+        assert_eq!(&data[32..36], &[0, 2, 0, 1]);
+        let data = &data[16 + 44 + 4..];
+        assert_eq!(&data[..12], &[0, 0, 0, 5, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&data[16..24], &[0, 0, 0, 1, 2, 0, 0, 0]);
+        assert_eq!(data.len(), 28);
+    }
+}
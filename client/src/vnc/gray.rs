@@ -0,0 +1,265 @@
+use crate::vnc::protocol::{Colour, PixelFormat};
+
+// Contribution of each primary to the luminance, in 1/65536ths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LumaWeights {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+}
+
+impl LumaWeights {
+    pub const REC601: LumaWeights = LumaWeights {
+        red: 19595,
+        green: 38470,
+        blue: 7471,
+    };
+    pub const REC709: LumaWeights = LumaWeights {
+        red: 13933,
+        green: 46871,
+        blue: 4732,
+    };
+}
+
+// An 8 bpp format carrying only the green channel. Gray content arrives
+// with all 256 levels intact and colours collapse to their green part,
+// for a quarter of the bandwidth of the usual 32 bpp.
+pub const GRAY8_FORMAT: PixelFormat = PixelFormat {
+    bits_per_pixel: 8,
+    depth: 8,
+    big_endian: false,
+    true_colour: true,
+    red_max: 0,
+    green_max: 255,
+    blue_max: 0,
+    red_shift: 0,
+    green_shift: 0,
+    blue_shift: 0,
+};
+
+// Turns pixels in any format into one luminance byte each. Indexed
+// pixels go through the colour map, kept here already converted to gray.
+pub struct GrayConverter {
+    format: PixelFormat,
+    weights: LumaWeights,
+    // (max, shift, factor) per channel, the factor scaling the raw channel
+    // value straight to its share of a 0-255 luminance in 1/65536ths.
+    channels: [(u32, u8, u32); 3],
+    palette: Vec<u8>,
+}
+
+impl GrayConverter {
+    pub fn new(format: PixelFormat, weights: LumaWeights) -> GrayConverter {
+        let channels = [
+            (format.red_max as u32, format.red_shift, weights.red),
+            (format.green_max as u32, format.green_shift, weights.green),
+            (format.blue_max as u32, format.blue_shift, weights.blue),
+        ];
+        // Channels the format doesn't carry can't contribute, so the weights
+        // of the others are scaled back up to a full range.
+        let total: u64 = channels
+            .iter()
+            .filter(|&&(max, _, _)| max > 0)
+            .map(|&(_, _, weight)| weight as u64)
+            .sum();
+        let channels = channels.map(|(max, shift, weight)| {
+            let factor = if max == 0 || total == 0 {
+                0
+            } else {
+                (weight as u64 * 65536 * 255 / total / max as u64) as u32
+            };
+            (max, shift, factor)
+        });
+        GrayConverter {
+            format,
+            weights,
+            channels,
+            palette: vec![0; 256],
+        }
+    }
+
+    pub fn set_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        let first = first_colour as usize;
+        if self.palette.len() < first + colours.len() {
+            self.palette.resize(first + colours.len(), 0);
+        }
+        let weights = self.weights;
+        for (entry, colour) in self.palette[first..].iter_mut().zip(colours) {
+            let luma = colour.red as u64 * weights.red as u64
+                + colour.green as u64 * weights.green as u64
+                + colour.blue as u64 * weights.blue as u64;
+            // 16-bit components, weights in 1/65536ths.
+            let luma = (luma + 0x8000) >> 16;
+            *entry = ((luma + 128) / 257).min(255) as u8;
+        }
+    }
+
+    pub fn pixel(&self, bytes: &[u8]) -> u8 {
+        let value = if self.format.big_endian {
+            bytes
+                .iter()
+                .fold(0, |value, &byte| value << 8 | byte as u32)
+        } else {
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u32)
+        };
+        if !self.format.true_colour {
+            // Entries the server never set stay black.
+            return self.palette.get(value as usize).copied().unwrap_or(0);
+        }
+
+        let luma: u32 = self
+            .channels
+            .iter()
+            .map(|&(max, shift, factor)| (value >> shift & max) * factor)
+            .sum();
+        ((luma + 0x8000) >> 16).min(255) as u8
+    }
+
+    pub fn convert(&self, pixels: &[u8]) -> Vec<u8> {
+        let bpp = self.format.bits_per_pixel as usize / 8;
+        pixels
+            .chunks_exact(bpp)
+            .map(|pixel| self.pixel(pixel))
+            .collect()
+    }
+}
+
+// Maps gray levels through the post processing filter: an S-curve around
+// the gray point, steeper for exponents above 1, and everything above the
+// white cutoff turned white.
+pub fn contrast_curve(exponent: f32, gray_point: f32, white_cutoff: u8) -> [u8; 256] {
+    let mut curve = [0; 256];
+    for (i, entry) in curve.iter_mut().enumerate() {
+        let value = if exponent == 1.0 {
+            i as u8
+        } else {
+            let gray = gray_point;
+
+            let rem_gray = 255.0 - gray;
+            let inv_exponent = 1.0 / exponent;
+
+            let raw_color = i as f32;
+            if raw_color < gray {
+                (gray * (raw_color / gray).powf(exponent)) as u8
+            } else if raw_color > gray {
+                (gray + rem_gray * ((raw_color - gray) / rem_gray).powf(inv_exponent)) as u8
+            } else {
+                gray as u8
+            }
+        };
+        *entry = if value > white_cutoff { 255 } else { value };
+    }
+    curve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contrast_curve, GrayConverter, LumaWeights, GRAY8_FORMAT};
+    use crate::vnc::protocol::{Colour, PixelFormat};
+
+    #[test]
+    fn test_convert_rgb565_big_endian() {
+        let format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        let converter = GrayConverter::new(format, LumaWeights::REC601);
+        let pixels = [0xff, 0xff, 0x00, 0x00, 0xf8, 0x00, 0x00, 0x1f];
+        assert_eq!(converter.convert(&pixels), vec![255, 0, 76, 29]);
+    }
+
+    #[test]
+    fn test_convert_bgrx_little_endian() {
+        let format = PixelFormat {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian: false,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        };
+        // Blue sits in byte 0, which used to be taken as the gray level.
+        let pixels = [0xff, 0x00, 0x00, 0x00, 0x80, 0x80, 0x80, 0x00];
+        let rec601 = GrayConverter::new(format, LumaWeights::REC601);
+        assert_eq!(rec601.convert(&pixels), vec![29, 128]);
+        let rec709 = GrayConverter::new(format, LumaWeights::REC709);
+        assert_eq!(rec709.convert(&pixels), vec![18, 128]);
+    }
+
+    #[test]
+    fn test_convert_gray8() {
+        let converter = GrayConverter::new(GRAY8_FORMAT, LumaWeights::REC709);
+        assert_eq!(converter.convert(&[0, 1, 128, 255]), vec![0, 1, 128, 255]);
+    }
+
+    #[test]
+    fn test_convert_indexed() {
+        let format = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian: false,
+            true_colour: false,
+            red_max: 0,
+            green_max: 0,
+            blue_max: 0,
+            red_shift: 0,
+            green_shift: 0,
+            blue_shift: 0,
+        };
+        let mut converter = GrayConverter::new(format, LumaWeights::REC601);
+        converter.set_colour_map(
+            1,
+            &[
+                Colour {
+                    red: 0xffff,
+                    green: 0xffff,
+                    blue: 0xffff,
+                },
+                Colour {
+                    red: 0,
+                    green: 0,
+                    blue: 0xffff,
+                },
+            ],
+        );
+        assert_eq!(converter.convert(&[0, 1, 2, 3]), vec![0, 255, 29, 0]);
+
+        converter.set_colour_map(
+            0,
+            &[Colour {
+                red: 0x8080,
+                green: 0x8080,
+                blue: 0x8080,
+            }],
+        );
+        assert_eq!(converter.convert(&[0, 1]), vec![128, 255]);
+    }
+
+    #[test]
+    fn test_contrast_curve() {
+        let identity = contrast_curve(1.0, 224.0, 255);
+        assert!(identity.iter().enumerate().all(|(i, &v)| v == i as u8));
+
+        let curve = contrast_curve(2.0, 128.0, 255);
+        assert_eq!((curve[0], curve[64], curve[128]), (0, 32, 128));
+        assert!(curve[192] > 192);
+
+        let cutoff = contrast_curve(1.0, 224.0, 200);
+        assert_eq!((cutoff[200], cutoff[201]), (200, 255));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::vnc::{protocol, Error, Rect, Result};
+use byteorder::ReadBytesExt;
+use std::io::Read;
+
+const RAW: u8 = 1 << 0;
+const BACKGROUND_SPECIFIED: u8 = 1 << 1;
+const FOREGROUND_SPECIFIED: u8 = 1 << 2;
+const ANY_SUBRECTS: u8 = 1 << 3;
+const SUBRECTS_COLOURED: u8 = 1 << 4;
+
+pub fn decode<R, F>(
+    format: protocol::PixelFormat,
+    rect: Rect,
+    reader: &mut R,
+    mut callback: F,
+) -> Result<bool>
+where
+    R: Read,
+    F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+{
+    let bpp = format.bits_per_pixel as usize / 8;
+
+    // Background and foreground colours carry over from one tile to the next
+    // within a rectangle, but not across rectangles.
+    let mut background: Option<Vec<u8>> = None;
+    let mut foreground: Option<Vec<u8>> = None;
+    let mut colour = vec![0; bpp];
+
+    let mut y = 0;
+    while y < rect.height {
+        let height = (rect.height - y).min(16);
+        let mut x = 0;
+        while x < rect.width {
+            let width = (rect.width - x).min(16);
+            let pixel_count = height as usize * width as usize;
+
+            let subencoding = reader.read_u8()?;
+            let pixels = if subencoding & RAW != 0 {
+                let mut pixels = vec![0; pixel_count * bpp];
+                reader.read_exact(&mut pixels)?;
+                pixels
+            } else {
+                if subencoding & BACKGROUND_SPECIFIED != 0 {
+                    let mut pixel = vec![0; bpp];
+                    reader.read_exact(&mut pixel)?;
+                    background = Some(pixel);
+                }
+                if subencoding & FOREGROUND_SPECIFIED != 0 {
+                    let mut pixel = vec![0; bpp];
+                    reader.read_exact(&mut pixel)?;
+                    foreground = Some(pixel);
+                }
+
+                let background = background
+                    .as_ref()
+                    .ok_or(Error::Unexpected("Hextile tile without background"))?;
+                let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                for _ in 0..pixel_count {
+                    pixels.extend_from_slice(background);
+                }
+
+                if subencoding & ANY_SUBRECTS != 0 {
+                    let count = reader.read_u8()?;
+                    for _ in 0..count {
+                        if subencoding & SUBRECTS_COLOURED != 0 {
+                            reader.read_exact(&mut colour)?;
+                        } else {
+                            let foreground = foreground
+                                .as_ref()
+                                .ok_or(Error::Unexpected("Hextile subrect without foreground"))?;
+                            colour.copy_from_slice(foreground);
+                        }
+
+                        let position = reader.read_u8()?;
+                        let size = reader.read_u8()?;
+                        let (sx, sy) = ((position >> 4) as u16, (position & 0xf) as u16);
+                        let (sw, sh) = ((size >> 4) as u16 + 1, (size & 0xf) as u16 + 1);
+                        if sx + sw > width || sy + sh > height {
+                            return Err(Error::Unexpected("Hextile subrect out of bounds"));
+                        }
+
+                        for row in sy..sy + sh {
+                            for column in sx..sx + sw {
+                                let start = (row as usize * width as usize + column as usize) * bpp;
+                                pixels[start..start + bpp].copy_from_slice(&colour);
+                            }
+                        }
+                    }
+                }
+
+                pixels
+            };
+
+            let tile = Rect {
+                top: rect.top + y,
+                left: rect.left + x,
+                width,
+                height,
+            };
+            if !callback(tile, pixels)? {
+                return Ok(false);
+            }
+
+            x += width;
+        }
+        y += height;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use crate::vnc::{protocol::PixelFormat, Rect};
+
+    const FORMAT: PixelFormat = PixelFormat {
+        bits_per_pixel: 8,
+        depth: 8,
+        big_endian: false,
+        true_colour: true,
+        red_max: 7,
+        green_max: 7,
+        blue_max: 3,
+        red_shift: 0,
+        green_shift: 3,
+        blue_shift: 6,
+    };
+
+    #[test]
+    fn test_decode_subrects() {
+        let rect = Rect {
+            left: 10,
+            top: 20,
+            width: 18,
+            height: 2,
+        };
+        let input: &[u8] = &[
+            // Tile 16x2: background 0xaa, foreground 0x55, one 2x1 subrect at (1, 1).
+            0b0000_1110,
+            0xaa,
+            0x55,
+            1,
+            0x11,
+            0x10,
+            // Tile 2x2: inherit the background, one coloured 1x2 subrect at (1, 0).
+            0b0001_1000,
+            1,
+            0x33,
+            0x10,
+            0x01,
+        ];
+
+        let mut tiles = Vec::new();
+        let result = decode(FORMAT, rect, &mut &input[..], |tile, pixels| {
+            tiles.push((tile, pixels));
+            Ok(true)
+        })
+        .unwrap();
+        assert!(result);
+        assert_eq!(tiles.len(), 2);
+
+        let (tile, ref pixels) = tiles[0];
+        assert_eq!(
+            tile,
+            Rect {
+                left: 10,
+                top: 20,
+                width: 16,
+                height: 2
+            }
+        );
+        let mut expected = vec![0xaa; 32];
+        expected[17] = 0x55;
+        expected[18] = 0x55;
+        assert_eq!(pixels, &expected);
+
+        let (tile, ref pixels) = tiles[1];
+        assert_eq!(
+            tile,
+            Rect {
+                left: 26,
+                top: 20,
+                width: 2,
+                height: 2
+            }
+        );
+        assert_eq!(pixels, &[0xaa, 0x33, 0xaa, 0x33]);
+    }
+
+    #[test]
+    fn test_decode_raw() {
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+        let input: &[u8] = &[0b0000_0001, 1, 2];
+        let mut tiles = Vec::new();
+        decode(FORMAT, rect, &mut &input[..], |tile, pixels| {
+            tiles.push((tile, pixels));
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(tiles, vec![(rect, vec![1, 2])]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::vnc::client::TlsConfig;
+use crate::vnc::{Error, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+
+// Fingerprints are written as colon separated hex bytes.
+pub fn fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// What a server is pinned under: its host and port, if any, with the
+// whitespace of `--via` commands and socket paths escaped as %XX so the
+// name stays one field of the line.
+fn pin_name(config: &TlsConfig) -> String {
+    let name = match config.port {
+        Some(port) if config.host.contains(':') => format!("[{}]:{}", config.host, port),
+        Some(port) => format!("{}:{}", config.host, port),
+        None => config.host.clone(),
+    };
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c == '%' || c.is_whitespace() {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// Trust on first use: the fingerprint of the first key seen for a server is
+// written down, and any other one is refused from then on. Certificates
+// and RSA-AES keys of the same server are told apart by `kind`.
+pub fn check(config: &TlsConfig, kind: &str, fingerprint: &str) -> Result<()> {
+    let name = pin_name(config);
+    let path = match config.known_hosts {
+        Some(ref path) => path,
+        None => {
+            warn!(
+                "accepting {} key {} of {} unverified",
+                kind, fingerprint, name
+            );
+            return Ok(());
+        }
+    };
+
+    let known_hosts = match fs::read_to_string(path) {
+        Ok(known_hosts) => known_hosts,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(Error::Io(error)),
+    };
+    for line in known_hosts.lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(host), Some(known_kind), Some(known)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            if host != name || known_kind != kind {
+                continue;
+            }
+            if known != fingerprint {
+                return Err(Error::AuthenticationFailure(format!(
+                    "{} key of {} changed to {}, expected {} from {}",
+                    kind,
+                    name,
+                    fingerprint,
+                    known,
+                    path.display()
+                )));
+            }
+            return Ok(());
+        }
+    }
+
+    info!(
+        "remembering {} key {} of {} in {}",
+        kind,
+        fingerprint,
+        name,
+        path.display()
+    );
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {} {}", name, kind, fingerprint)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::vnc::client::TlsConfig;
+    use std::fs;
+
+    #[test]
+    fn test_known_hosts() {
+        let path = std::env::temp_dir().join(format!("einkvnc-known-hosts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = |host: &str| TlsConfig {
+            ca_file: None,
+            known_hosts: Some(path.clone()),
+            host: String::from(host),
+            port: None,
+        };
+
+        check(&config("desktop"), "x509", "aa:bb").unwrap();
+        check(&config("laptop"), "x509", "cc:dd").unwrap();
+        check(&config("desktop"), "rsa", "ee:ff").unwrap();
+        check(&config("desktop"), "x509", "aa:bb").unwrap();
+        assert!(check(&config("desktop"), "x509", "cc:dd").is_err());
+        assert!(check(&config("desktop"), "rsa", "aa:bb").is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "desktop x509 aa:bb\nlaptop x509 cc:dd\ndesktop rsa ee:ff\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_known_hosts_names() {
+        let path = std::env::temp_dir().join(format!("einkvnc-known-names-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = |host: &str, port: Option<u16>| TlsConfig {
+            ca_file: None,
+            known_hosts: Some(path.clone()),
+            host: String::from(host),
+            port,
+        };
+
+        // A --via command is pinned like any other name.
+        let via = config("ssh desktop nc localhost 5900", None);
+        check(&via, "rsa", "aa:bb").unwrap();
+        check(&via, "rsa", "aa:bb").unwrap();
+        assert!(check(&via, "rsa", "cc:dd").is_err());
+
+        // Servers on the same machine don't share pins.
+        check(&config("desktop", Some(5900)), "x509", "aa:bb").unwrap();
+        check(&config("desktop", Some(5901)), "x509", "cc:dd").unwrap();
+        assert!(check(&config("desktop", Some(5900)), "x509", "cc:dd").is_err());
+        check(&config("::1", Some(5900)), "x509", "ee:ff").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "ssh%20desktop%20nc%20localhost%205900 rsa aa:bb\n\
+             desktop:5900 x509 aa:bb\n\
+             desktop:5901 x509 cc:dd\n\
+             [::1]:5900 x509 ee:ff\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
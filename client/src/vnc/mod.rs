@@ -1,11 +1,35 @@
-mod protocol;
+mod hextile;
+#[cfg(any(feature = "tls", feature = "rsa-aes"))]
+mod known_hosts;
+pub(crate) mod protocol;
+mod rre;
+#[cfg(feature = "rsa-aes")]
+mod rsa_aes;
+mod tight;
+#[cfg(feature = "tls")]
+mod tls;
+mod transcode;
+mod translate;
+mod viewer;
+mod zlib;
 mod zrle;
 
 pub mod client;
+pub mod fbs;
+pub mod gray;
 pub mod proxy;
 
 pub use client::Client;
-pub use protocol::{Colour, Encoding, PixelFormat};
+pub use gray::{GrayConverter, LumaWeights};
+pub use protocol::{set_limits, Colour, Encoding, Limits, PixelFormat};
+
+// What the fuzz targets feed with arbitrary bytes.
+#[cfg(fuzzing)]
+pub mod fuzz {
+    pub use super::protocol::{Message, Rectangle, S2C};
+    pub use super::zrle::Decoder as ZrleDecoder;
+}
+pub use transcode::Transcode;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Rect {
@@ -22,6 +46,8 @@ pub enum Error {
     Server(String),
     AuthenticationUnavailable,
     AuthenticationFailure(String),
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    Tls(String),
     Disconnected,
 }
 
@@ -31,10 +57,12 @@ impl std::fmt::Display for Error {
             Error::Io(ref inner) => inner.fmt(f),
             Error::Unexpected(ref descr) => write!(f, "unexpected {}", descr),
             Error::Server(ref descr) => write!(f, "server error: {}", descr),
+            Error::AuthenticationUnavailable => f.write_str("no usable authentication method"),
             Error::AuthenticationFailure(ref descr) => {
                 write!(f, "authentication failure: {}", descr)
             }
-            _ => f.write_str(&self.to_string()),
+            Error::Tls(ref descr) => write!(f, "TLS error: {}", descr),
+            Error::Disconnected => f.write_str("disconnected"),
         }
     }
 }
@@ -1,6 +1,7 @@
 use crate::vnc::{Error, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait Message {
     fn read_from<R: Read>(reader: &mut R) -> Result<Self>
@@ -9,12 +10,70 @@ pub trait Message {
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()>;
 }
 
+const DEFAULT_MAX_LENGTH: usize = 32 << 20;
+const DEFAULT_MAX_PIXELS: usize = 8192 * 8192;
+
+static MAX_LENGTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_LENGTH);
+static MAX_PIXELS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_PIXELS);
+
+// How much a peer can make the reader allocate. Anything larger is an
+// error, rather than an allocation that takes the device down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Bytes of a string or of length-prefixed data, like clipboard text or
+    // a ZRLE rectangle.
+    pub max_length: usize,
+    // Pixels of a framebuffer or a cursor.
+    pub max_pixels: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_length: DEFAULT_MAX_LENGTH,
+            max_pixels: DEFAULT_MAX_PIXELS,
+        }
+    }
+}
+
+// For every connection from then on.
+pub fn set_limits(limits: Limits) {
+    MAX_LENGTH.store(limits.max_length, Ordering::Relaxed);
+    MAX_PIXELS.store(limits.max_pixels, Ordering::Relaxed);
+}
+
+pub fn limits() -> Limits {
+    Limits {
+        max_length: MAX_LENGTH.load(Ordering::Relaxed),
+        max_pixels: MAX_PIXELS.load(Ordering::Relaxed),
+    }
+}
+
+// Reads as much as the peer says it sends, growing the buffer as the data
+// arrives instead of trusting the length up front.
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    if length > limits().max_length {
+        return Err(Error::Unexpected("length over the limit"));
+    }
+    let mut buffer = Vec::new();
+    reader.take(length as u64).read_to_end(&mut buffer)?;
+    if buffer.len() < length {
+        return Err(std::io::Error::from(IoErrorKind::UnexpectedEof).into());
+    }
+    Ok(buffer)
+}
+
+pub fn check_size(width: u16, height: u16) -> Result<()> {
+    if width as usize * height as usize > limits().max_pixels {
+        return Err(Error::Unexpected("size over the limit"));
+    }
+    Ok(())
+}
+
 impl Message for Vec<u8> {
     fn read_from<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
         let length = reader.read_u32::<BigEndian>()?;
-        let mut buffer = vec![0; length as usize];
-        reader.read_exact(&mut buffer)?;
-        Ok(buffer)
+        read_bytes(reader, length as usize)
     }
 
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
@@ -30,8 +89,7 @@ are embedded in Unicode. */
 impl Message for String {
     fn read_from<R: Read>(reader: &mut R) -> Result<String> {
         let length = reader.read_u32::<BigEndian>()?;
-        let mut string = vec![0; length as usize];
-        reader.read_exact(&mut string)?;
+        let string = read_bytes(reader, length as usize)?;
         Ok(string.iter().map(|c| *c as char).collect())
     }
 
@@ -82,7 +140,13 @@ pub enum SecurityType {
     None,
     VncAuthentication,
     // extensions
+    RsaAes,
+    RsaAesUnencrypted,
+    Tls,
+    VeNCrypt,
     AppleRemoteDesktop,
+    RsaAes256,
+    RsaAes256Unencrypted,
 }
 
 impl Message for SecurityType {
@@ -92,7 +156,13 @@ impl Message for SecurityType {
             0 => Ok(SecurityType::Invalid),
             1 => Ok(SecurityType::None),
             2 => Ok(SecurityType::VncAuthentication),
+            5 => Ok(SecurityType::RsaAes),
+            6 => Ok(SecurityType::RsaAesUnencrypted),
+            18 => Ok(SecurityType::Tls),
+            19 => Ok(SecurityType::VeNCrypt),
             30 => Ok(SecurityType::AppleRemoteDesktop),
+            129 => Ok(SecurityType::RsaAes256),
+            130 => Ok(SecurityType::RsaAes256Unencrypted),
             n => Ok(SecurityType::Unknown(n)),
         }
     }
@@ -102,7 +172,13 @@ impl Message for SecurityType {
             SecurityType::Invalid => 0,
             SecurityType::None => 1,
             SecurityType::VncAuthentication => 2,
+            SecurityType::RsaAes => 5,
+            SecurityType::RsaAesUnencrypted => 6,
+            SecurityType::Tls => 18,
+            SecurityType::VeNCrypt => 19,
             SecurityType::AppleRemoteDesktop => 30,
+            SecurityType::RsaAes256 => 129,
+            SecurityType::RsaAes256Unencrypted => 130,
             SecurityType::Unknown(n) => *n,
         };
         writer.write_u8(security_type)?;
@@ -133,6 +209,81 @@ impl Message for SecurityTypes {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub enum VeNCryptSubtype {
+    Unknown(u32),
+    Plain,
+    TlsNone,
+    TlsVnc,
+    TlsPlain,
+    X509None,
+    X509Vnc,
+    X509Plain,
+}
+
+impl Message for VeNCryptSubtype {
+    fn read_from<R: Read>(reader: &mut R) -> Result<VeNCryptSubtype> {
+        match reader.read_u32::<BigEndian>()? {
+            256 => Ok(VeNCryptSubtype::Plain),
+            257 => Ok(VeNCryptSubtype::TlsNone),
+            258 => Ok(VeNCryptSubtype::TlsVnc),
+            259 => Ok(VeNCryptSubtype::TlsPlain),
+            260 => Ok(VeNCryptSubtype::X509None),
+            261 => Ok(VeNCryptSubtype::X509Vnc),
+            262 => Ok(VeNCryptSubtype::X509Plain),
+            n => Ok(VeNCryptSubtype::Unknown(n)),
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let subtype = match self {
+            VeNCryptSubtype::Plain => 256,
+            VeNCryptSubtype::TlsNone => 257,
+            VeNCryptSubtype::TlsVnc => 258,
+            VeNCryptSubtype::TlsPlain => 259,
+            VeNCryptSubtype::X509None => 260,
+            VeNCryptSubtype::X509Vnc => 261,
+            VeNCryptSubtype::X509Plain => 262,
+            VeNCryptSubtype::Unknown(n) => *n,
+        };
+        writer.write_u32::<BigEndian>(subtype)?;
+        Ok(())
+    }
+}
+
+// The credentials of the VeNCrypt Plain subtypes, sent in the clear or over
+// TLS depending on the subtype.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct PlainCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Message for PlainCredentials {
+    fn read_from<R: Read>(reader: &mut R) -> Result<PlainCredentials> {
+        let username_length = reader.read_u32::<BigEndian>()?;
+        let password_length = reader.read_u32::<BigEndian>()?;
+        let username = read_bytes(reader, username_length as usize)?;
+        let password = read_bytes(reader, password_length as usize)?;
+        Ok(PlainCredentials {
+            username: String::from_utf8(username)
+                .map_err(|_| Error::Unexpected("non-UTF-8 username"))?,
+            password: String::from_utf8(password)
+                .map_err(|_| Error::Unexpected("non-UTF-8 password"))?,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.username.len() as u32)?;
+        writer.write_u32::<BigEndian>(self.password.len() as u32)?;
+        writer.write_all(self.username.as_bytes())?;
+        writer.write_all(self.password.as_bytes())?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SecurityResult {
     Succeeded,
@@ -159,6 +310,7 @@ impl Message for SecurityResult {
     }
 }
 
+#[cfg(feature = "apple-auth")]
 #[derive(Debug)]
 pub struct AppleAuthHandshake {
     pub generator: u16,
@@ -166,6 +318,7 @@ pub struct AppleAuthHandshake {
     pub peer_key: Vec<u8>,
 }
 
+#[cfg(feature = "apple-auth")]
 impl Message for AppleAuthHandshake {
     fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
         let generator = reader.read_u16::<BigEndian>()?;
@@ -189,12 +342,14 @@ impl Message for AppleAuthHandshake {
     }
 }
 
+#[cfg(feature = "apple-auth")]
 #[allow(dead_code)]
 pub struct AppleAuthResponse {
     pub ciphertext: [u8; 128],
     pub pub_key: Vec<u8>,
 }
 
+#[cfg(feature = "apple-auth")]
 impl Message for AppleAuthResponse {
     fn read_from<R: Read>(_reader: &mut R) -> Result<Self> {
         unreachable!()
@@ -254,6 +409,18 @@ impl Message for PixelFormat {
             blue_shift: reader.read_u8()?,
         };
         reader.read_exact(&mut [0u8; 3])?;
+        // Pixels are read and shifted as whole bytes of a u32.
+        if ![8, 16, 32].contains(&pixel_format.bits_per_pixel)
+            || [
+                pixel_format.red_shift,
+                pixel_format.green_shift,
+                pixel_format.blue_shift,
+            ]
+            .iter()
+            .any(|&shift| shift >= 32)
+        {
+            return Err(Error::Unexpected("pixel format"));
+        }
         Ok(pixel_format)
     }
 
@@ -273,7 +440,7 @@ impl Message for PixelFormat {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ServerInit {
     pub framebuffer_width: u16,
     pub framebuffer_height: u16,
@@ -283,9 +450,12 @@ pub struct ServerInit {
 
 impl Message for ServerInit {
     fn read_from<R: Read>(reader: &mut R) -> Result<ServerInit> {
+        let framebuffer_width = reader.read_u16::<BigEndian>()?;
+        let framebuffer_height = reader.read_u16::<BigEndian>()?;
+        check_size(framebuffer_width, framebuffer_height)?;
         Ok(ServerInit {
-            framebuffer_width: reader.read_u16::<BigEndian>()?,
-            framebuffer_height: reader.read_u16::<BigEndian>()?,
+            framebuffer_width,
+            framebuffer_height,
             pixel_format: PixelFormat::read_from(reader)?,
             name: String::read_from(reader)?,
         })
@@ -328,11 +498,38 @@ pub enum Encoding {
     Raw,
     CopyRect,
     Rre,
+    CoRre,
     Hextile,
+    Zlib,
+    Tight,
+    Trle,
     Zrle,
     Cursor,
+    PointerPos,
     DesktopSize,
     // extensions
+    CompressLevel(u8),
+    QualityLevel(u8),
+    ExtendedDesktopSize,
+    Fence,
+    ContinuousUpdates,
+}
+
+impl Encoding {
+    // Whether its rectangles carry something other than framebuffer pixels.
+    pub fn is_pseudo(&self) -> bool {
+        matches!(
+            self,
+            Encoding::Cursor
+                | Encoding::PointerPos
+                | Encoding::DesktopSize
+                | Encoding::CompressLevel(_)
+                | Encoding::QualityLevel(_)
+                | Encoding::ExtendedDesktopSize
+                | Encoding::Fence
+                | Encoding::ContinuousUpdates
+        )
+    }
 }
 
 impl Message for Encoding {
@@ -342,10 +539,20 @@ impl Message for Encoding {
             0 => Ok(Encoding::Raw),
             1 => Ok(Encoding::CopyRect),
             2 => Ok(Encoding::Rre),
+            4 => Ok(Encoding::CoRre),
             5 => Ok(Encoding::Hextile),
+            6 => Ok(Encoding::Zlib),
+            7 => Ok(Encoding::Tight),
+            15 => Ok(Encoding::Trle),
             16 => Ok(Encoding::Zrle),
             -239 => Ok(Encoding::Cursor),
+            -232 => Ok(Encoding::PointerPos),
             -223 => Ok(Encoding::DesktopSize),
+            -308 => Ok(Encoding::ExtendedDesktopSize),
+            -312 => Ok(Encoding::Fence),
+            -313 => Ok(Encoding::ContinuousUpdates),
+            n @ -256..=-247 => Ok(Encoding::CompressLevel((n + 256) as u8)),
+            n @ -32..=-23 => Ok(Encoding::QualityLevel((n + 32) as u8)),
             n => Ok(Encoding::Unknown(n)),
         }
     }
@@ -355,10 +562,20 @@ impl Message for Encoding {
             Encoding::Raw => 0,
             Encoding::CopyRect => 1,
             Encoding::Rre => 2,
+            Encoding::CoRre => 4,
             Encoding::Hextile => 5,
+            Encoding::Zlib => 6,
+            Encoding::Tight => 7,
+            Encoding::Trle => 15,
             Encoding::Zrle => 16,
             Encoding::Cursor => -239,
+            Encoding::PointerPos => -232,
             Encoding::DesktopSize => -223,
+            Encoding::CompressLevel(level) => -256 + *level as i32,
+            Encoding::QualityLevel(level) => -32 + *level as i32,
+            Encoding::ExtendedDesktopSize => -308,
+            Encoding::Fence => -312,
+            Encoding::ContinuousUpdates => -313,
             Encoding::Unknown(n) => *n,
         };
         writer.write_i32::<BigEndian>(encoding)?;
@@ -366,6 +583,88 @@ impl Message for Encoding {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Screen {
+    pub id: u32,
+    pub x_position: u16,
+    pub y_position: u16,
+    pub width: u16,
+    pub height: u16,
+    pub flags: u32,
+}
+
+impl Message for Screen {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Screen> {
+        Ok(Screen {
+            id: reader.read_u32::<BigEndian>()?,
+            x_position: reader.read_u16::<BigEndian>()?,
+            y_position: reader.read_u16::<BigEndian>()?,
+            width: reader.read_u16::<BigEndian>()?,
+            height: reader.read_u16::<BigEndian>()?,
+            flags: reader.read_u32::<BigEndian>()?,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.id)?;
+        writer.write_u16::<BigEndian>(self.x_position)?;
+        writer.write_u16::<BigEndian>(self.y_position)?;
+        writer.write_u16::<BigEndian>(self.width)?;
+        writer.write_u16::<BigEndian>(self.height)?;
+        writer.write_u32::<BigEndian>(self.flags)?;
+        Ok(())
+    }
+}
+
+/* The payload of an ExtendedDesktopSize rectangle. */
+#[derive(Debug)]
+pub struct ScreenLayout(pub Vec<Screen>);
+
+impl Message for ScreenLayout {
+    fn read_from<R: Read>(reader: &mut R) -> Result<ScreenLayout> {
+        let count = reader.read_u8()?;
+        reader.read_exact(&mut [0u8; 3])?;
+        let mut screens = Vec::new();
+        for _ in 0..count {
+            screens.push(Screen::read_from(reader)?);
+        }
+        Ok(ScreenLayout(screens))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u8(self.0.len() as u8)?; // TODO: check?
+        writer.write_all(&[0u8; 3])?;
+        for screen in &self.0 {
+            Screen::write_to(screen, writer)?;
+        }
+        Ok(())
+    }
+}
+
+pub const FENCE_BLOCK_BEFORE: u32 = 1 << 0;
+pub const FENCE_BLOCK_AFTER: u32 = 1 << 1;
+pub const FENCE_REQUEST: u32 = 1 << 31;
+
+fn read_fence<R: Read>(reader: &mut R) -> Result<(u32, Vec<u8>)> {
+    reader.read_exact(&mut [0u8; 3])?;
+    let flags = reader.read_u32::<BigEndian>()?;
+    let length = reader.read_u8()?;
+    if length > 64 {
+        return Err(Error::Unexpected("fence payload length"));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    Ok((flags, payload))
+}
+
+fn write_fence<W: Write>(writer: &mut W, flags: u32, payload: &[u8]) -> Result<()> {
+    writer.write_all(&[0u8; 3])?;
+    writer.write_u32::<BigEndian>(flags)?;
+    writer.write_u8(payload.len() as u8)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum C2S {
     // core spec
@@ -389,6 +688,22 @@ pub enum C2S {
     },
     CutText(String),
     // extensions
+    SetDesktopSize {
+        width: u16,
+        height: u16,
+        screens: Vec<Screen>,
+    },
+    EnableContinuousUpdates {
+        enable: bool,
+        x_position: u16,
+        y_position: u16,
+        width: u16,
+        height: u16,
+    },
+    ClientFence {
+        flags: u32,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message for C2S {
@@ -435,6 +750,33 @@ impl Message for C2S {
                 reader.read_exact(&mut [0u8; 3])?;
                 Ok(C2S::CutText(String::read_from(reader)?))
             }
+            251 => {
+                reader.read_exact(&mut [0u8; 1])?;
+                let width = reader.read_u16::<BigEndian>()?;
+                let height = reader.read_u16::<BigEndian>()?;
+                let count = reader.read_u8()?;
+                reader.read_exact(&mut [0u8; 1])?;
+                let mut screens = Vec::new();
+                for _ in 0..count {
+                    screens.push(Screen::read_from(reader)?);
+                }
+                Ok(C2S::SetDesktopSize {
+                    width,
+                    height,
+                    screens,
+                })
+            }
+            150 => Ok(C2S::EnableContinuousUpdates {
+                enable: reader.read_u8()? != 0,
+                x_position: reader.read_u16::<BigEndian>()?,
+                y_position: reader.read_u16::<BigEndian>()?,
+                width: reader.read_u16::<BigEndian>()?,
+                height: reader.read_u16::<BigEndian>()?,
+            }),
+            248 => {
+                let (flags, payload) = read_fence(reader)?;
+                Ok(C2S::ClientFence { flags, payload })
+            }
             _ => Err(Error::Unexpected("client to server message type")),
         }
     }
@@ -486,6 +828,39 @@ impl Message for C2S {
             C2S::CutText(ref text) => {
                 String::write_to(text, writer)?;
             }
+            C2S::SetDesktopSize {
+                width,
+                height,
+                ref screens,
+            } => {
+                writer.write_u8(251)?;
+                writer.write_all(&[0u8; 1])?;
+                writer.write_u16::<BigEndian>(*width)?;
+                writer.write_u16::<BigEndian>(*height)?;
+                writer.write_u8(screens.len() as u8)?; // TODO: check?
+                writer.write_all(&[0u8; 1])?;
+                for screen in screens {
+                    Screen::write_to(screen, writer)?;
+                }
+            }
+            C2S::EnableContinuousUpdates {
+                enable,
+                x_position,
+                y_position,
+                width,
+                height,
+            } => {
+                writer.write_u8(150)?;
+                writer.write_u8(if *enable { 1 } else { 0 })?;
+                writer.write_u16::<BigEndian>(*x_position)?;
+                writer.write_u16::<BigEndian>(*y_position)?;
+                writer.write_u16::<BigEndian>(*width)?;
+                writer.write_u16::<BigEndian>(*height)?;
+            }
+            C2S::ClientFence { flags, ref payload } => {
+                writer.write_u8(248)?;
+                write_fence(writer, *flags, payload)?;
+            }
         }
         Ok(())
     }
@@ -521,7 +896,7 @@ impl Message for Rectangle {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Colour {
     pub red: u16,
     pub green: u16,
@@ -559,6 +934,11 @@ pub enum S2C {
     Bell,
     CutText(String),
     // extensions
+    EndOfContinuousUpdates,
+    ServerFence {
+        flags: u32,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message for S2C {
@@ -594,6 +974,11 @@ impl Message for S2C {
                 reader.read_exact(&mut [0u8; 3])?;
                 Ok(S2C::CutText(String::read_from(reader)?))
             }
+            150 => Ok(S2C::EndOfContinuousUpdates),
+            248 => {
+                let (flags, payload) = read_fence(reader)?;
+                Ok(S2C::ServerFence { flags, payload })
+            }
             _ => Err(Error::Unexpected("server to client message type")),
         }
     }
@@ -612,6 +997,7 @@ impl Message for S2C {
                 writer.write_u8(1)?;
                 writer.write_all(&[0u8; 1])?;
                 writer.write_u16::<BigEndian>(*first_colour)?;
+                writer.write_u16::<BigEndian>(colours.len() as u16)?;
                 for colour in colours {
                     Colour::write_to(colour, writer)?;
                 }
@@ -624,7 +1010,110 @@ impl Message for S2C {
                 writer.write_all(&[0u8; 3])?;
                 String::write_to(text, writer)?;
             }
+            S2C::EndOfContinuousUpdates => {
+                writer.write_u8(150)?;
+            }
+            S2C::ServerFence { flags, ref payload } => {
+                writer.write_u8(248)?;
+                write_fence(writer, *flags, payload)?;
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Colour, Message, PixelFormat, C2S, FENCE_BLOCK_BEFORE, FENCE_REQUEST, S2C};
+
+    #[test]
+    fn test_fence_round_trip() {
+        let input: &[u8] = &[248, 0, 0, 0, 0x80, 0, 0, 1, 2, 0xab, 0xcd];
+        match S2C::read_from(&mut &input[..]).unwrap() {
+            S2C::ServerFence { flags, payload } => {
+                assert_eq!(flags, FENCE_REQUEST | FENCE_BLOCK_BEFORE);
+                assert_eq!(payload, vec![0xab, 0xcd]);
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+
+        let mut output = Vec::new();
+        C2S::ClientFence {
+            flags: FENCE_BLOCK_BEFORE,
+            payload: vec![0xab, 0xcd],
+        }
+        .write_to(&mut output)
+        .unwrap();
+        assert_eq!(output, &[248, 0, 0, 0, 0, 0, 0, 1, 2, 0xab, 0xcd]);
+    }
+
+    #[test]
+    fn test_enable_continuous_updates() {
+        let mut output = Vec::new();
+        C2S::EnableContinuousUpdates {
+            enable: true,
+            x_position: 0,
+            y_position: 0,
+            width: 800,
+            height: 600,
+        }
+        .write_to(&mut output)
+        .unwrap();
+        assert_eq!(output, &[150, 1, 0, 0, 0, 0, 3, 0x20, 2, 0x58]);
+        assert!(matches!(
+            S2C::read_from(&mut &[150u8][..]).unwrap(),
+            S2C::EndOfContinuousUpdates
+        ));
+    }
+
+    #[test]
+    fn test_colour_map_round_trip() {
+        let colours = vec![
+            Colour {
+                red: 0xffff,
+                green: 0,
+                blue: 0x8080,
+            },
+            Colour {
+                red: 0,
+                green: 0x1234,
+                blue: 0,
+            },
+        ];
+        let mut output = Vec::new();
+        S2C::SetColourMapEntries {
+            first_colour: 3,
+            colours: colours.clone(),
+        }
+        .write_to(&mut output)
+        .unwrap();
+        match S2C::read_from(&mut &output[..]).unwrap() {
+            S2C::SetColourMapEntries {
+                first_colour,
+                colours: read,
+            } => {
+                assert_eq!(first_colour, 3);
+                assert_eq!(read, colours);
+            }
+            packet => panic!("unexpected {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        // Over the limit, rejected before anything is read or allocated.
+        let input: &[u8] = &[3, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        assert!(S2C::read_from(&mut &input[..]).is_err());
+        // Under it but cut short.
+        let input: &[u8] = &[3, 0, 0, 0, 0, 0, 0, 4, b'a', b'b'];
+        assert!(S2C::read_from(&mut &input[..]).is_err());
+        let input: &[u8] = &[3, 0, 0, 0, 0, 0, 0, 2, b'a', b'b'];
+        assert!(
+            matches!(S2C::read_from(&mut &input[..]).unwrap(), S2C::CutText(text) if text == "ab")
+        );
+
+        // 24 bits per pixel, which nothing decodes.
+        let input: &[u8] = &[24, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+        assert!(PixelFormat::read_from(&mut &input[..]).is_err());
+    }
+}
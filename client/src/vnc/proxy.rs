@@ -1,273 +1,644 @@
 // use crate::protocol::{self, Message};
-use crate::vnc::protocol::Message;
-use crate::vnc::{Error, Result};
-use std::io::{Cursor, Read, Write};
+use crate::vnc::client::{self, AuthChoice, AuthMethod, Client, Event, Sink, TlsConfig};
+use crate::vnc::protocol::{Encoding, Message};
+use crate::vnc::transcode::{Change, Desktop, Transcode};
+use crate::vnc::viewer::Viewer;
+use crate::vnc::{fbs, Error, Rect, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::vnc::protocol;
 
-pub struct Proxy {
-    c2s_thread: thread::JoinHandle<Result<()>>,
-    s2c_thread: thread::JoinHandle<Result<()>>,
+// Everything the proxy can decode, and the pseudo-encodings it passes on to
+// the viewers that want them.
+const UPSTREAM_ENCODINGS: [Encoding; 12] = [
+    Encoding::Tight,
+    Encoding::CopyRect,
+    Encoding::Zrle,
+    Encoding::Trle,
+    Encoding::Zlib,
+    Encoding::Hextile,
+    Encoding::CoRre,
+    Encoding::Rre,
+    Encoding::Cursor,
+    Encoding::PointerPos,
+    Encoding::DesktopSize,
+    Encoding::ExtendedDesktopSize,
+];
+
+// The server connection and the viewers sharing it. The server only ever
+// sees what the proxy asks of it, and keeps the pixel format it started
+// with.
+struct Hub {
+    server: Box<dyn Sink>,
+    name: String,
+    desktop: Desktop,
+    // Whether the server has an update request it hasn't answered yet.
+    requested: bool,
+    viewers: HashMap<usize, Viewer>,
+    next_viewer: usize,
+    // Once the server is gone, for viewers arriving too late.
+    closed: bool,
 }
 
-impl Proxy {
-    pub fn from_tcp_streams(
-        mut server_stream: TcpStream,
-        mut client_stream: TcpStream,
-    ) -> Result<Proxy> {
-        let server_version = protocol::Version::read_from(&mut server_stream)?;
-        debug!("c<-s {:?}", server_version);
-        protocol::Version::write_to(&server_version, &mut client_stream)?;
-
-        let client_version = protocol::Version::read_from(&mut client_stream)?;
-        debug!("c->s {:?}", client_version);
-        protocol::Version::write_to(&client_version, &mut server_stream)?;
-
-        fn security_type_supported(security_type: &protocol::SecurityType) -> bool {
-            match security_type {
-                &protocol::SecurityType::None => true,
-                security_type => {
-                    warn!("security type {:?} is not supported", security_type);
-                    false
-                }
-            }
+impl Hub {
+    fn request_update(&mut self) -> Result<()> {
+        protocol::C2S::FramebufferUpdateRequest {
+            incremental: self.desktop.ready,
+            x_position: 0,
+            y_position: 0,
+            width: self.desktop.size.0,
+            height: self.desktop.size.1,
         }
+        .write_to(&mut self.server)?;
+        self.server.flush()?;
+        self.requested = true;
+        Ok(())
+    }
 
-        let security_types = match client_version {
-            protocol::Version::Rfb33 => {
-                let mut security_type = protocol::SecurityType::read_from(&mut server_stream)?;
-                debug!("!<-s SecurityType::{:?}", security_type);
-
-                // Filter out security types we can't handle
-                if !security_type_supported(&security_type) {
-                    security_type = protocol::SecurityType::Invalid
-                }
-
-                debug!("c<-! SecurityType::{:?}", security_type);
-                protocol::SecurityType::write_to(&security_type, &mut client_stream)?;
-
-                if security_type == protocol::SecurityType::Invalid {
-                    vec![]
-                } else {
-                    vec![security_type]
-                }
+    fn handle(&mut self, event: Event) -> Result<()> {
+        let change = match self.desktop.handle(event)? {
+            Some(change) => change,
+            None => return Ok(()),
+        };
+        // A viewer that can't be written to anymore has left.
+        let desktop = &self.desktop;
+        self.viewers
+            .retain(|_, viewer| viewer.change(&change, desktop).is_ok());
+        if let Change::EndOfFrame = change {
+            self.requested = false;
+            let mut waiting = false;
+            self.viewers
+                .retain(|_, viewer| match viewer.update(desktop) {
+                    Ok(still_waiting) => {
+                        waiting |= still_waiting;
+                        true
+                    }
+                    Err(_) => false,
+                });
+            // The server is only asked for more while a viewer waits for it.
+            if waiting {
+                self.request_update()?;
             }
-            _ => {
-                let mut security_types = protocol::SecurityTypes::read_from(&mut server_stream)?;
-                debug!("!<-s {:?}", security_types);
-
-                // Filter out security types we can't handle
-                security_types.0.retain(security_type_supported);
+        }
+        Ok(())
+    }
+}
 
-                debug!("c<-! {:?}", security_types);
-                protocol::SecurityTypes::write_to(&security_types, &mut client_stream)?;
+// One connection to a server, shared by every viewer that connects to the
+// proxy. Viewers come and go without the server noticing: each gets what
+// changed since its last update whenever it asks for the next one, in its
+// own pixel format.
+pub struct Proxy {
+    hub: Arc<Mutex<Hub>>,
+    viewer_password: Option<[u8; 8]>,
+    thread: thread::JoinHandle<()>,
+}
 
-                security_types.0
+// Lets a viewer in with the proxy's own password, if it has one, so that
+// the server's never leaves the proxy. Returns what the viewer said in its
+// ClientInit.
+fn accept_viewer(
+    client_stream: &mut TcpStream,
+    password: Option<[u8; 8]>,
+) -> Result<protocol::ClientInit> {
+    protocol::Version::Rfb38.write_to(client_stream)?;
+    let version = protocol::Version::read_from(client_stream)?;
+    debug!("c->! {:?}", version);
+
+    let security_type = match password {
+        Some(_) => protocol::SecurityType::VncAuthentication,
+        None => protocol::SecurityType::None,
+    };
+    match version {
+        // 3.3 viewers are told the security type as a u32 and don't choose.
+        protocol::Version::Rfb33 => {
+            client_stream.write_all(&[0, 0, 0])?;
+            security_type.write_to(client_stream)?;
+        }
+        _ => {
+            protocol::SecurityTypes(vec![security_type]).write_to(client_stream)?;
+            let used_security_type = protocol::SecurityType::read_from(client_stream)?;
+            debug!("c->! SecurityType::{:?}", used_security_type);
+            if used_security_type != security_type {
+                return Err(Error::Unexpected("security type"));
             }
-        };
-
-        if security_types.is_empty() {
-            let reason = String::read_from(&mut server_stream)?;
-            debug!("c<-s {:?}", reason);
-            String::write_to(&reason, &mut client_stream)?;
+        }
+    }
 
-            return Err(Error::Server(reason));
+    if let Some(password) = password {
+        let mut challenge = [0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut challenge)?;
+        client_stream.write_all(&challenge)?;
+        let mut response = [0; 16];
+        client_stream.read_exact(&mut response)?;
+        if response[..] != client::challenge_response(&challenge, password)[..] {
+            protocol::SecurityResult::Failed.write_to(client_stream)?;
+            let reason = String::from("wrong password");
+            if version == protocol::Version::Rfb38 {
+                reason.write_to(client_stream)?;
+            }
+            return Err(Error::AuthenticationFailure(reason));
         }
+    }
+    if password.is_some() || version == protocol::Version::Rfb38 {
+        protocol::SecurityResult::Succeeded.write_to(client_stream)?;
+    }
 
-        let used_security_type = match client_version {
-            protocol::Version::Rfb33 => security_types[0],
-            _ => {
-                let used_security_type = protocol::SecurityType::read_from(&mut client_stream)?;
-                debug!("c->s SecurityType::{:?}", used_security_type);
-                protocol::SecurityType::write_to(&used_security_type, &mut server_stream)?;
+    let client_init = protocol::ClientInit::read_from(client_stream)?;
+    debug!("c->! {:?}", client_init);
+    Ok(client_init)
+}
 
-                used_security_type
-            }
+impl Proxy {
+    // The server authenticates the proxy with whatever `auth` picks, just as
+    // it would a Client, and the proxy authenticates viewers with
+    // `viewer_password`, or lets them all in when it's None. With a
+    // transcode, viewers see an 8 bpp gray framebuffer, whatever the server
+    // sends. With `record`, what the server sends is recorded to that file.
+    pub fn from_tcp_stream<Auth>(
+        server_stream: TcpStream,
+        tls: &TlsConfig,
+        auth: Auth,
+        viewer_password: Option<[u8; 8]>,
+        transcode: Option<Transcode>,
+        record: Option<&Path>,
+    ) -> Result<Proxy>
+    where
+        Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice>,
+    {
+        // Viewers can't agree on whether to share the desktop, so it's
+        // shared.
+        let (server_reader, server_writer, server_init) =
+            Client::handshake(server_stream.try_clone()?, server_stream, true, tls, auth)?;
+        debug!("!<-s {:?}", server_init);
+        let recorder = match record {
+            Some(path) => Some(fbs::Recorder::create(path, &server_init)?),
+            None => None,
         };
 
-        let mut skip_security_result = false;
-        match &(used_security_type, client_version) {
-            &(protocol::SecurityType::None, protocol::Version::Rfb33)
-            | &(protocol::SecurityType::None, protocol::Version::Rfb37) => {
-                skip_security_result = true
+        let size = (
+            server_init.framebuffer_width,
+            server_init.framebuffer_height,
+        );
+        let mut hub = Hub {
+            server: server_writer,
+            name: server_init.name,
+            desktop: Desktop::new(server_init.pixel_format, size, transcode),
+            requested: false,
+            viewers: HashMap::new(),
+            next_viewer: 0,
+            closed: false,
+        };
+        protocol::C2S::SetEncodings(UPSTREAM_ENCODINGS.to_vec()).write_to(&mut hub.server)?;
+        hub.request_update()?;
+        let hub = Arc::new(Mutex::new(hub));
+
+        let (tx_events, rx_events) = channel();
+        let format = Arc::new(Mutex::new(server_init.pixel_format));
+        thread::spawn(move || {
+            let mut tx_events = tx_events;
+            let error = Event::pump(server_reader, format, size, recorder, &mut tx_events).err();
+            let _ = tx_events.send(Event::Disconnected(error));
+        });
+
+        fn relay(hub: &Mutex<Hub>, rx_events: Receiver<Event>) -> Result<()> {
+            for event in rx_events {
+                hub.lock().unwrap().handle(event)?;
             }
-            _ => (),
+            Err(Error::Disconnected)
         }
 
-        if !skip_security_result {
-            let security_result = protocol::SecurityResult::read_from(&mut server_stream)?;
-            debug!("c<-s SecurityResult::{:?}", security_result);
-            protocol::SecurityResult::write_to(&security_result, &mut client_stream)?;
-
-            if security_result == protocol::SecurityResult::Failed {
-                match client_version {
-                    protocol::Version::Rfb33 | protocol::Version::Rfb37 => {
-                        return Err(Error::AuthenticationFailure(String::from("")))
-                    }
-                    protocol::Version::Rfb38 => {
-                        let reason = String::read_from(&mut server_stream)?;
-                        debug!("c<-s {:?}", reason);
-                        String::write_to(&reason, &mut client_stream)?;
-                        return Err(Error::AuthenticationFailure(reason));
-                    }
-                }
+        let relay_hub = hub.clone();
+        let thread = thread::spawn(move || {
+            let result = relay(&relay_hub, rx_events);
+            let mut hub = relay_hub.lock().unwrap();
+            hub.closed = true;
+            let _ = hub.server.close();
+            // Which has the writers close the viewers' connections.
+            hub.viewers.clear();
+            match result {
+                Ok(()) | Err(Error::Disconnected) => info!("server disconnected"),
+                Err(error) => error!("server disconnected: {}", error),
             }
-        }
+        });
 
-        let client_init = protocol::ClientInit::read_from(&mut client_stream)?;
-        debug!("c->s {:?}", client_init);
-        protocol::ClientInit::write_to(&client_init, &mut server_stream)?;
-
-        let server_init = protocol::ServerInit::read_from(&mut server_stream)?;
-        debug!("c<-s {:?}", server_init);
-        protocol::ServerInit::write_to(&server_init, &mut client_stream)?;
+        Ok(Proxy {
+            hub,
+            viewer_password,
+            thread,
+        })
+    }
 
-        let (mut c2s_server_stream, mut c2s_client_stream) = (
-            server_stream.try_clone().unwrap(),
-            client_stream.try_clone().unwrap(),
-        );
-        let (mut s2c_server_stream, mut s2c_client_stream) = (
-            server_stream.try_clone().unwrap(),
-            client_stream.try_clone().unwrap(),
-        );
+    // Whether the server is gone, and viewers need a new proxy.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
 
-        fn forward_c2s(server_stream: &mut TcpStream, client_stream: &mut TcpStream) -> Result<()> {
-            fn encoding_supported(encoding: &protocol::Encoding) -> bool {
-                match encoding {
-                    &protocol::Encoding::Raw
-                    | &protocol::Encoding::CopyRect
-                    | &protocol::Encoding::Zrle
-                    | &protocol::Encoding::Cursor
-                    | &protocol::Encoding::DesktopSize => true,
-                    encoding => {
-                        warn!("encoding {:?} is not supported", encoding);
-                        false
-                    }
+    // Lets a viewer in and relays between it and the server until either
+    // leaves.
+    pub fn serve(&self, mut client_stream: TcpStream) -> Result<()> {
+        accept_viewer(&mut client_stream, self.viewer_password)?;
+
+        // Updates are written on a thread of their own, so that a viewer on a
+        // slow network holds up neither the server nor the others.
+        let (tx_messages, rx_messages) = channel::<Vec<u8>>();
+        let mut writer = client_stream.try_clone()?;
+        thread::spawn(move || {
+            for message in rx_messages {
+                if writer.write_all(&message).is_err() {
+                    break;
                 }
             }
+            let _ = writer.shutdown(Shutdown::Both);
+        });
 
-            loop {
-                let mut message = protocol::C2S::read_from(client_stream)?;
-                match message {
-                    protocol::C2S::SetEncodings(ref mut encodings) => {
-                        debug!("c->! SetEncodings({:?})", encodings);
+        let id = {
+            let mut hub = self.hub.lock().unwrap();
+            if hub.closed {
+                return Err(Error::Disconnected);
+            }
+            let server_init = protocol::ServerInit {
+                framebuffer_width: hub.desktop.size.0,
+                framebuffer_height: hub.desktop.size.1,
+                pixel_format: hub.desktop.format(),
+                name: hub.name.clone(),
+            };
+            debug!("c<-! {:?}", server_init);
+            let mut message = Vec::new();
+            server_init.write_to(&mut message)?;
+            tx_messages.send(message).map_err(|_| Error::Disconnected)?;
+            let id = hub.next_viewer;
+            hub.next_viewer += 1;
+            let viewer = Viewer::new(tx_messages, &hub.desktop);
+            hub.viewers.insert(id, viewer);
+            id
+        };
 
-                        // Filter out encodings we can't handle
-                        encodings.retain(encoding_supported);
+        let result = self.forward(id, &mut client_stream);
+        self.hub.lock().unwrap().viewers.remove(&id);
+        let _ = client_stream.shutdown(Shutdown::Both);
+        match result {
+            Err(Error::Disconnected) => Ok(()),
+            result => result,
+        }
+    }
 
-                        debug!("!->s SetEncodings({:?})", encodings);
-                    }
-                    protocol::C2S::SetPixelFormat(_) => {
-                        // There is an inherent race condition in the VNC protocol (I think)
-                        // between SetPixelFormat and FramebufferUpdate and I've no idea
-                        // how to handle it properly, so defer for now.
-                        panic!("proxying SetPixelFormat is not implemented!")
+    fn forward(&self, id: usize, client_stream: &mut TcpStream) -> Result<()> {
+        loop {
+            let message = protocol::C2S::read_from(client_stream)?;
+            let mut hub = self.hub.lock().unwrap();
+            let hub = &mut *hub;
+            let viewer = hub.viewers.get_mut(&id).ok_or(Error::Disconnected)?;
+            match message {
+                protocol::C2S::SetEncodings(encodings) => {
+                    debug!("c->! SetEncodings({:?})", encodings);
+                    viewer.set_encodings(encodings, &hub.desktop)?;
+                }
+                protocol::C2S::SetPixelFormat(format) => {
+                    debug!("c->! SetPixelFormat({:?})", format);
+                    // Passing it on would race with the updates already on
+                    // their way in the old format, and change it for every
+                    // other viewer. The proxy translates instead.
+                    viewer.format = format;
+                }
+                protocol::C2S::FramebufferUpdateRequest {
+                    incremental,
+                    x_position,
+                    y_position,
+                    width,
+                    height,
+                } => {
+                    let rect = Rect {
+                        left: x_position,
+                        top: y_position,
+                        width,
+                        height,
+                    };
+                    if viewer.request(incremental, rect, &hub.desktop)? && !hub.requested {
+                        hub.request_update()?;
                     }
-                    ref message => debug!("c->s {:?}", message),
                 }
-                protocol::C2S::write_to(&message, server_stream)?
+                // Never offered to viewers.
+                protocol::C2S::EnableContinuousUpdates { .. }
+                | protocol::C2S::ClientFence { .. } => debug!("c->! {:?}", message),
+                message => {
+                    debug!("c->s {:?}", message);
+                    message.write_to(&mut hub.server)?;
+                    hub.server.flush()?;
+                }
             }
         }
+    }
+}
 
-        fn forward_s2c(
-            server_stream: &mut TcpStream,
-            client_stream: &mut TcpStream,
-            format: protocol::PixelFormat,
-        ) -> Result<()> {
-            loop {
-                let mut buffer_stream = Cursor::new(Vec::new());
-
-                let message = protocol::S2C::read_from(server_stream)?;
-                debug!("c<-s {:?}", message);
-                protocol::S2C::write_to(&message, &mut buffer_stream)?;
-
-                match message {
-                    protocol::S2C::FramebufferUpdate { count } => {
-                        for _ in 0..count {
-                            let rectangle = protocol::Rectangle::read_from(server_stream)?;
-                            debug!("c<-s {:?}", rectangle);
-                            protocol::Rectangle::write_to(&rectangle, &mut buffer_stream)?;
-
-                            match rectangle.encoding {
-                                protocol::Encoding::Raw => {
-                                    let mut pixels = vec![
-                                        0;
-                                        (rectangle.width as usize)
-                                            * (rectangle.height as usize)
-                                            * (format.bits_per_pixel as usize / 8)
-                                    ];
-                                    server_stream.read_exact(&mut pixels)?;
-                                    debug!("c<-s ...raw pixels");
-                                    buffer_stream.write_all(&pixels)?;
-                                }
-                                protocol::Encoding::CopyRect => {
-                                    let copy_rect = protocol::CopyRect::read_from(server_stream)?;
-                                    debug!("c<-s {:?}", copy_rect);
-                                    protocol::CopyRect::write_to(&copy_rect, &mut buffer_stream)?;
-                                }
-                                protocol::Encoding::Zrle => {
-                                    let zrle = Vec::<u8>::read_from(server_stream)?;
-                                    debug!("c<-s ...ZRLE pixels");
-                                    Vec::<u8>::write_to(&zrle, &mut buffer_stream)?;
-                                }
-                                protocol::Encoding::Cursor => {
-                                    let mut pixels = vec![
-                                        0;
-                                        (rectangle.width as usize)
-                                            * (rectangle.height as usize)
-                                            * (format.bits_per_pixel as usize / 8)
-                                    ];
-                                    server_stream.read_exact(&mut pixels)?;
-                                    buffer_stream.write_all(&pixels)?;
-                                    let mut mask_bits = vec![
-                                        0;
-                                        ((rectangle.width as usize + 7) / 8)
-                                            * (rectangle.height as usize)
-                                    ];
-                                    server_stream.read_exact(&mut mask_bits)?;
-                                    buffer_stream.write_all(&mask_bits)?;
-                                }
-                                protocol::Encoding::DesktopSize => (),
-                                _ => return Err(Error::Unexpected("encoding")),
-                            }
-                        }
+#[cfg(test)]
+mod tests {
+    use super::Proxy;
+    use crate::vnc::client::{self, AuthChoice, AuthMethod, TlsConfig};
+    use crate::vnc::protocol::{self, Encoding, Message, PixelFormat, C2S, S2C};
+    use crate::vnc::zrle;
+    use crate::vnc::Error;
+    use crate::vnc::Rect;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    const BGRX: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    const RGB565: PixelFormat = PixelFormat {
+        bits_per_pixel: 16,
+        depth: 16,
+        big_endian: true,
+        true_colour: true,
+        red_max: 31,
+        green_max: 63,
+        blue_max: 31,
+        red_shift: 11,
+        green_shift: 5,
+        blue_shift: 0,
+    };
+
+    // A server that answers every update request with a red and a blue
+    // pixel, as ZRLE, and never changes its format.
+    fn serve(mut stream: TcpStream, password: Option<[u8; 8]>) -> crate::vnc::Result<()> {
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+        protocol::Version::Rfb38.write_to(&mut stream)?;
+        protocol::Version::read_from(&mut stream)?;
+        match password {
+            Some(password) => {
+                protocol::SecurityTypes(vec![protocol::SecurityType::VncAuthentication])
+                    .write_to(&mut stream)?;
+                protocol::SecurityType::read_from(&mut stream)?;
+                let challenge = [7; 16];
+                stream.write_all(&challenge)?;
+                let mut response = [0; 16];
+                stream.read_exact(&mut response)?;
+                assert_eq!(
+                    response[..],
+                    client::challenge_response(&challenge, password)[..]
+                );
+            }
+            None => {
+                protocol::SecurityTypes(vec![protocol::SecurityType::None])
+                    .write_to(&mut stream)?;
+                protocol::SecurityType::read_from(&mut stream)?;
+            }
+        }
+        protocol::SecurityResult::Succeeded.write_to(&mut stream)?;
+        protocol::ClientInit::read_from(&mut stream)?;
+        protocol::ServerInit {
+            framebuffer_width: rect.width,
+            framebuffer_height: rect.height,
+            pixel_format: BGRX,
+            name: String::from("test"),
+        }
+        .write_to(&mut stream)?;
+
+        let mut encoder = zrle::Encoder::new();
+        loop {
+            match C2S::read_from(&mut stream)? {
+                C2S::SetPixelFormat(_) => panic!("the server's format changed"),
+                C2S::FramebufferUpdateRequest { .. } => {
+                    S2C::FramebufferUpdate { count: 1 }.write_to(&mut stream)?;
+                    protocol::Rectangle {
+                        x_position: rect.left,
+                        y_position: rect.top,
+                        width: rect.width,
+                        height: rect.height,
+                        encoding: Encoding::Zrle,
                     }
-                    _ => (),
+                    .write_to(&mut stream)?;
+                    let pixels = [0, 0, 0xff, 0, 0xff, 0, 0, 0];
+                    encoder
+                        .encode(BGRX, rect.width, rect.height, &pixels)?
+                        .write_to(&mut stream)?;
                 }
-
-                let buffer = buffer_stream.into_inner();
-                client_stream.write_all(&buffer)?;
+                _ => (),
             }
         }
+    }
 
-        Ok(Proxy {
-            c2s_thread: thread::spawn(move || {
-                let result = forward_c2s(&mut c2s_server_stream, &mut c2s_client_stream);
-                let _ = c2s_server_stream.shutdown(Shutdown::Both);
-                let _ = c2s_client_stream.shutdown(Shutdown::Both);
-                result
-            }),
-            s2c_thread: thread::spawn(move || {
-                let result = forward_s2c(
-                    &mut s2c_server_stream,
-                    &mut s2c_client_stream,
-                    server_init.pixel_format,
-                );
-                let _ = s2c_server_stream.shutdown(Shutdown::Both);
-                let _ = s2c_client_stream.shutdown(Shutdown::Both);
-                result
-            }),
-        })
+    // A proxy in front of `serve`, with a password for each.
+    fn start(server_password: Option<[u8; 8]>, viewer_password: Option<[u8; 8]>) -> Arc<Proxy> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_address = server_listener.local_addr().unwrap();
+        thread::spawn(move || serve(server_listener.accept().unwrap().0, server_password));
+
+        let server_stream = TcpStream::connect(server_address).unwrap();
+        let auth = |methods: &[AuthMethod]| match methods {
+            [AuthMethod::Password] => server_password.map(AuthChoice::Password),
+            _ => Some(AuthChoice::None),
+        };
+        let proxy = Proxy::from_tcp_stream(
+            server_stream,
+            &TlsConfig::default(),
+            auth,
+            viewer_password,
+            None,
+            None,
+        )
+        .unwrap();
+        Arc::new(proxy)
     }
 
-    pub fn join(self) -> Result<()> {
-        let c2s_result = self.c2s_thread.join().unwrap();
-        let s2c_result = self.s2c_thread.join().unwrap();
-        match c2s_result.and(s2c_result) {
-            Err(Error::Disconnected) => Ok(()),
-            result => result,
+    // A viewer of the proxy, served on a thread of its own.
+    fn connect(proxy: &Arc<Proxy>) -> (TcpStream, thread::JoinHandle<crate::vnc::Result<()>>) {
+        let client_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(client_listener.local_addr().unwrap()).unwrap();
+        let client_stream = client_listener.accept().unwrap().0;
+        let proxy = Arc::clone(proxy);
+        (client, thread::spawn(move || proxy.serve(client_stream)))
+    }
+
+    // Up to the ServerInit, for a proxy without a viewer password.
+    fn handshake(client: &mut TcpStream) -> protocol::ServerInit {
+        protocol::Version::read_from(client).unwrap();
+        protocol::Version::Rfb38.write_to(client).unwrap();
+        protocol::SecurityTypes::read_from(client).unwrap();
+        protocol::SecurityType::None.write_to(client).unwrap();
+        protocol::SecurityResult::read_from(client).unwrap();
+        protocol::ClientInit { shared: true }
+            .write_to(client)
+            .unwrap();
+        let server_init = protocol::ServerInit::read_from(client).unwrap();
+        C2S::SetEncodings(vec![Encoding::Zrle])
+            .write_to(client)
+            .unwrap();
+        server_init
+    }
+
+    // Asks for an update of the whole 2x1 desktop and decodes it.
+    fn update(
+        client: &mut TcpStream,
+        decoder: &mut zrle::Decoder,
+        format: PixelFormat,
+        incremental: bool,
+    ) -> Vec<u8> {
+        C2S::FramebufferUpdateRequest {
+            incremental,
+            x_position: 0,
+            y_position: 0,
+            width: 2,
+            height: 1,
         }
+        .write_to(client)
+        .unwrap();
+        assert!(matches!(
+            S2C::read_from(client).unwrap(),
+            S2C::FramebufferUpdate { count: 1 }
+        ));
+        let rectangle = protocol::Rectangle::read_from(client).unwrap();
+        assert_eq!(rectangle.encoding, Encoding::Zrle);
+        let data = Vec::<u8>::read_from(client).unwrap();
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+        let mut pixels = Vec::new();
+        decoder
+            .decode(format, rect, &data, |_, tile_pixels| {
+                pixels.extend(tile_pixels);
+                Ok(true)
+            })
+            .unwrap();
+        pixels
+    }
+
+    #[test]
+    fn test_set_pixel_format() {
+        let proxy = start(None, None);
+        let (mut client, viewer) = connect(&proxy);
+        let server_init = handshake(&mut client);
+        assert_eq!(server_init.pixel_format, BGRX);
+
+        // One update in the server's format, one in the new one, all on
+        // the same zlib stream.
+        let mut decoder = zrle::Decoder::new();
+        assert_eq!(
+            update(&mut client, &mut decoder, BGRX, false),
+            vec![0, 0, 0xff, 0, 0xff, 0, 0, 0]
+        );
+        C2S::SetPixelFormat(RGB565).write_to(&mut client).unwrap();
+        assert_eq!(
+            update(&mut client, &mut decoder, RGB565, false),
+            vec![0xf8, 0x00, 0x00, 0x1f]
+        );
+
+        drop(client);
+        viewer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_viewers() {
+        let proxy = start(None, None);
+        let (mut first, first_viewer) = connect(&proxy);
+        handshake(&mut first);
+        let mut first_decoder = zrle::Decoder::new();
+        assert_eq!(
+            update(&mut first, &mut first_decoder, BGRX, false),
+            vec![0, 0, 0xff, 0, 0xff, 0, 0, 0]
+        );
+
+        // A second viewer gets the whole desktop as it joins, in a format of
+        // its own, while the server keeps its format and its one connection.
+        let (mut second, second_viewer) = connect(&proxy);
+        handshake(&mut second);
+        C2S::SetPixelFormat(RGB565).write_to(&mut second).unwrap();
+        let mut second_decoder = zrle::Decoder::new();
+        assert_eq!(
+            update(&mut second, &mut second_decoder, RGB565, false),
+            vec![0xf8, 0x00, 0x00, 0x1f]
+        );
+
+        // Nothing changed for either until the server sends more, which it
+        // does when asked for it.
+        assert_eq!(
+            update(&mut first, &mut first_decoder, BGRX, true),
+            vec![0, 0, 0xff, 0, 0xff, 0, 0, 0]
+        );
+        drop(first);
+        first_viewer.join().unwrap().unwrap();
+
+        assert_eq!(
+            update(&mut second, &mut second_decoder, RGB565, true),
+            vec![0xf8, 0x00, 0x00, 0x1f]
+        );
+        drop(second);
+        second_viewer.join().unwrap().unwrap();
+        assert!(!proxy.is_finished());
+    }
+
+    #[test]
+    fn test_viewer_password() {
+        let server_password = *b"server\0\0";
+        let viewer_password = *b"viewer\0\0";
+        let proxy = start(Some(server_password), Some(viewer_password));
+        let authenticate = |password: [u8; 8]| {
+            let (mut client, viewer) = connect(&proxy);
+            protocol::Version::read_from(&mut client).unwrap();
+            protocol::Version::Rfb38.write_to(&mut client).unwrap();
+            assert_eq!(
+                protocol::SecurityTypes::read_from(&mut client).unwrap().0,
+                vec![protocol::SecurityType::VncAuthentication]
+            );
+            protocol::SecurityType::VncAuthentication
+                .write_to(&mut client)
+                .unwrap();
+            let mut challenge = [0; 16];
+            client.read_exact(&mut challenge).unwrap();
+            client
+                .write_all(&client::challenge_response(&challenge, password))
+                .unwrap();
+            let result = protocol::SecurityResult::read_from(&mut client).unwrap();
+            if result == protocol::SecurityResult::Succeeded {
+                protocol::ClientInit { shared: true }
+                    .write_to(&mut client)
+                    .unwrap();
+                let server_init = protocol::ServerInit::read_from(&mut client).unwrap();
+                assert_eq!(server_init.name, "test");
+            } else {
+                assert_eq!(String::read_from(&mut client).unwrap(), "wrong password");
+            }
+            drop(client);
+            (result, viewer.join().unwrap())
+        };
+
+        // The server's password is only ever used by the proxy.
+        let (result, viewer_result) = authenticate(server_password);
+        assert_eq!(result, protocol::SecurityResult::Failed);
+        assert!(matches!(
+            viewer_result,
+            Err(Error::AuthenticationFailure(_))
+        ));
+
+        let (result, viewer_result) = authenticate(viewer_password);
+        assert_eq!(result, protocol::SecurityResult::Succeeded);
+        assert!(viewer_result.is_ok());
     }
 }
@@ -0,0 +1,128 @@
+use crate::vnc::{protocol, Error, Rect, Result};
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::Read;
+
+// Decodes an RRE rectangle, or a CoRRE one if `compact` is set. Rather than
+// expanding the rectangle into a pixel buffer, the background and every
+// sub-rectangle are passed to `callback` as a single pixel to be filled in.
+pub fn decode<R, F>(
+    format: protocol::PixelFormat,
+    rect: Rect,
+    reader: &mut R,
+    compact: bool,
+    mut callback: F,
+) -> Result<bool>
+where
+    R: Read,
+    F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+{
+    let bpp = format.bits_per_pixel as usize / 8;
+
+    let count = reader.read_u32::<BigEndian>()?;
+    let mut background = vec![0; bpp];
+    reader.read_exact(&mut background)?;
+    if !callback(rect, background)? {
+        return Ok(false);
+    }
+
+    for _ in 0..count {
+        let mut pixel = vec![0; bpp];
+        reader.read_exact(&mut pixel)?;
+        let (x, y, width, height) = if compact {
+            (
+                reader.read_u8()? as u16,
+                reader.read_u8()? as u16,
+                reader.read_u8()? as u16,
+                reader.read_u8()? as u16,
+            )
+        } else {
+            (
+                reader.read_u16::<BigEndian>()?,
+                reader.read_u16::<BigEndian>()?,
+                reader.read_u16::<BigEndian>()?,
+                reader.read_u16::<BigEndian>()?,
+            )
+        };
+        if x as u32 + width as u32 > rect.width as u32
+            || y as u32 + height as u32 > rect.height as u32
+        {
+            return Err(Error::Unexpected("RRE subrect out of bounds"));
+        }
+
+        let subrect = Rect {
+            left: rect.left + x,
+            top: rect.top + y,
+            width,
+            height,
+        };
+        if !callback(subrect, pixel)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use crate::vnc::{protocol::PixelFormat, Rect};
+
+    const FORMAT: PixelFormat = PixelFormat {
+        bits_per_pixel: 16,
+        depth: 16,
+        big_endian: false,
+        true_colour: true,
+        red_max: 31,
+        green_max: 63,
+        blue_max: 31,
+        red_shift: 11,
+        green_shift: 5,
+        blue_shift: 0,
+    };
+
+    #[test]
+    fn test_decode_corre() {
+        let rect = Rect {
+            left: 100,
+            top: 200,
+            width: 20,
+            height: 10,
+        };
+        let input: &[u8] = &[0, 0, 0, 1, 0xff, 0xff, 0x00, 0x00, 2, 3, 4, 5];
+        let mut fills = Vec::new();
+        let result = decode(FORMAT, rect, &mut &input[..], true, |rect, pixel| {
+            fills.push((rect, pixel));
+            Ok(true)
+        })
+        .unwrap();
+        assert!(result);
+        assert_eq!(
+            fills,
+            vec![
+                (rect, vec![0xff, 0xff]),
+                (
+                    Rect {
+                        left: 102,
+                        top: 203,
+                        width: 4,
+                        height: 5
+                    },
+                    vec![0x00, 0x00]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_rre_out_of_bounds() {
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 4,
+            height: 4,
+        };
+        let input: &[u8] = &[0, 0, 0, 1, 0xff, 0xff, 0x00, 0x00, 0, 2, 0, 0, 0, 3, 0, 1];
+        assert!(decode(FORMAT, rect, &mut &input[..], false, |_, _| Ok(true)).is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use crate::vnc::client::{Sink, TlsConfig};
+use crate::vnc::known_hosts;
+use crate::vnc::{Error, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use eax::aead::{Aead, KeyInit, Payload};
+use eax::Eax;
+use rand::RngCore;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::sync::OnceLock;
+
+const MIN_KEY_LENGTH: u32 = 1024;
+const MAX_KEY_LENGTH: u32 = 8192;
+const CLIENT_KEY_LENGTH: usize = 2048;
+// Largest frame we send, as in TigerVNC.
+const MAX_MESSAGE_SIZE: usize = 8192;
+const TAG_SIZE: usize = 16;
+
+const SUBTYPE_USER_PASS: u8 = 1;
+const SUBTYPE_PASS: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySize {
+    Aes128,
+    Aes256,
+}
+
+enum Cipher {
+    Aes128(Eax<aes::Aes128>),
+    Aes256(Eax<aes::Aes256>),
+}
+
+// One direction of the session: every frame is sealed with AES-EAX under a
+// nonce counting up from zero, little endian, and its length as associated
+// data.
+struct Frames {
+    cipher: Cipher,
+    counter: [u8; 16],
+}
+
+impl Frames {
+    fn new(key_size: KeySize, key: &[u8]) -> Frames {
+        let cipher = match key_size {
+            KeySize::Aes128 => Cipher::Aes128(Eax::new_from_slice(key).unwrap()),
+            KeySize::Aes256 => Cipher::Aes256(Eax::new_from_slice(key).unwrap()),
+        };
+        Frames {
+            cipher,
+            counter: [0; 16],
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 16] {
+        let nonce = self.counter;
+        for byte in self.counter.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    fn seal(&mut self, message: &[u8]) -> Vec<u8> {
+        let length = (message.len() as u16).to_be_bytes();
+        let nonce = self.next_nonce();
+        let payload = Payload {
+            msg: message,
+            aad: &length,
+        };
+        let sealed = match self.cipher {
+            Cipher::Aes128(ref cipher) => cipher.encrypt(&nonce.into(), payload),
+            Cipher::Aes256(ref cipher) => cipher.encrypt(&nonce.into(), payload),
+        }
+        .unwrap();
+        let mut frame = Vec::with_capacity(2 + sealed.len());
+        frame.extend_from_slice(&length);
+        frame.extend_from_slice(&sealed);
+        frame
+    }
+
+    fn open(&mut self, length: u16, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let length = length.to_be_bytes();
+        let nonce = self.next_nonce();
+        let payload = Payload {
+            msg: sealed,
+            aad: &length,
+        };
+        match self.cipher {
+            Cipher::Aes128(ref cipher) => cipher.decrypt(&nonce.into(), payload),
+            Cipher::Aes256(ref cipher) => cipher.decrypt(&nonce.into(), payload),
+        }
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "RSA-AES frame forged"))
+    }
+}
+
+pub struct AesReader {
+    reader: Box<dyn Read + Send>,
+    frames: Frames,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl Read for AesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            let length = match self.reader.read_u16::<BigEndian>() {
+                Ok(length) => length,
+                Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(error) => return Err(error),
+            };
+            let mut sealed = vec![0; length as usize + TAG_SIZE];
+            self.reader.read_exact(&mut sealed)?;
+            self.plaintext = self.frames.open(length, &sealed)?;
+            self.position = 0;
+        }
+        let count = buf.len().min(self.plaintext.len() - self.position);
+        buf[..count].copy_from_slice(&self.plaintext[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+// Writes are collected into one frame until flushed, which the client does
+// after every message.
+pub struct AesWriter {
+    writer: Box<dyn Sink>,
+    frames: Frames,
+    plaintext: Vec<u8>,
+}
+
+impl AesWriter {
+    fn write_frame(&mut self) -> io::Result<()> {
+        if !self.plaintext.is_empty() {
+            let frame = self.frames.seal(&self.plaintext);
+            self.plaintext.clear();
+            self.writer.write_all(&frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AesWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.plaintext.len() == MAX_MESSAGE_SIZE {
+            self.write_frame()?;
+        }
+        let count = buf.len().min(MAX_MESSAGE_SIZE - self.plaintext.len());
+        self.plaintext.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frame()?;
+        self.writer.flush()
+    }
+}
+
+impl Sink for AesWriter {
+    fn close(&mut self) -> io::Result<()> {
+        let _ = self.write_frame();
+        self.writer.close()
+    }
+}
+
+// Generating a key takes a while on an e-reader, so one serves every
+// connection of the session.
+fn client_key() -> Result<&'static RsaPrivateKey> {
+    static CLIENT_KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+    if let Some(key) = CLIENT_KEY.get() {
+        return Ok(key);
+    }
+    debug!("generating a {}-bit RSA key", CLIENT_KEY_LENGTH);
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), CLIENT_KEY_LENGTH)
+        .map_err(|error| Error::AuthenticationFailure(error.to_string()))?;
+    Ok(CLIENT_KEY.get_or_init(|| key))
+}
+
+// A public key as sent on the wire: its length in bits, then the modulus and
+// the exponent padded to the same number of bytes.
+fn encode_key(key: &RsaPublicKey) -> Vec<u8> {
+    let bits = key.n().bits() as u32;
+    let size = (bits as usize).div_ceil(8);
+    let mut encoded = bits.to_be_bytes().to_vec();
+    for part in [key.n(), key.e()] {
+        let bytes = part.to_bytes_be();
+        encoded.resize(encoded.len() + size - bytes.len(), 0);
+        encoded.extend_from_slice(&bytes);
+    }
+    encoded
+}
+
+fn digest(key_size: KeySize, parts: &[&[u8]]) -> Vec<u8> {
+    match key_size {
+        KeySize::Aes128 => {
+            let mut hasher = Sha1::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize().to_vec()
+        }
+        KeySize::Aes256 => {
+            let mut hasher = Sha256::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize().to_vec()
+        }
+    }
+}
+
+// Runs the RSA-AES handshake up to and including the credentials. When
+// `encrypt_session` is false, as in the RA2ne types, the halves returned
+// are the plain ones again.
+pub fn connect(
+    mut reader: Box<dyn Read + Send>,
+    mut writer: Box<dyn Sink>,
+    config: &TlsConfig,
+    key_size: KeySize,
+    encrypt_session: bool,
+    username: &str,
+    password: &str,
+) -> Result<(Box<dyn Read + Send>, Box<dyn Sink>)> {
+    let server_key_length = reader.read_u32::<BigEndian>()?;
+    if !(MIN_KEY_LENGTH..=MAX_KEY_LENGTH).contains(&server_key_length) {
+        return Err(Error::Unexpected("RSA-AES server key length"));
+    }
+    let server_key_size = (server_key_length as usize).div_ceil(8);
+    let mut server_key = vec![0; 4 + 2 * server_key_size];
+    server_key[..4].copy_from_slice(&server_key_length.to_be_bytes());
+    reader.read_exact(&mut server_key[4..])?;
+    debug!("<- RSA-AES server key, {} bits", server_key_length);
+
+    known_hosts::check(
+        config,
+        "rsa",
+        &known_hosts::fingerprint(&Sha256::digest(&server_key)),
+    )?;
+    let server_public_key = RsaPublicKey::new_with_max_size(
+        BigUint::from_bytes_be(&server_key[4..4 + server_key_size]),
+        BigUint::from_bytes_be(&server_key[4 + server_key_size..]),
+        MAX_KEY_LENGTH as usize,
+    )
+    .map_err(|_| Error::Unexpected("RSA-AES server key"))?;
+
+    let client_private_key = client_key()?;
+    let client_key = encode_key(&client_private_key.to_public_key());
+    let client_key_size = client_private_key.size();
+    writer.write_all(&client_key)?;
+
+    // The randoms are as long as the AES key they make.
+    let key_length = match key_size {
+        KeySize::Aes128 => 16,
+        KeySize::Aes256 => 32,
+    };
+    let mut rng = rand::thread_rng();
+    let mut client_random = vec![0; key_length];
+    rng.fill_bytes(&mut client_random);
+    let encrypted = server_public_key
+        .encrypt(&mut rng, Pkcs1v15Encrypt, &client_random)
+        .map_err(|error| Error::AuthenticationFailure(error.to_string()))?;
+    writer.write_u16::<BigEndian>(encrypted.len() as u16)?;
+    writer.write_all(&encrypted)?;
+    writer.flush()?;
+
+    if reader.read_u16::<BigEndian>()? as usize != client_key_size {
+        return Err(Error::Unexpected("RSA-AES server random length"));
+    }
+    let mut encrypted = vec![0; client_key_size];
+    reader.read_exact(&mut encrypted)?;
+    let server_random = client_private_key
+        .decrypt(Pkcs1v15Encrypt, &encrypted)
+        .map_err(|_| Error::Unexpected("RSA-AES server random"))?;
+    if server_random.len() != key_length {
+        return Err(Error::Unexpected("RSA-AES server random length"));
+    }
+
+    // Each side's session key comes from the other's random first.
+    let client_session_key = digest(key_size, &[&server_random, &client_random]);
+    let server_session_key = digest(key_size, &[&client_random, &server_random]);
+    let mut aes_reader = AesReader {
+        reader,
+        frames: Frames::new(key_size, &server_session_key[..key_length]),
+        plaintext: Vec::new(),
+        position: 0,
+    };
+    let mut aes_writer = AesWriter {
+        writer,
+        frames: Frames::new(key_size, &client_session_key[..key_length]),
+        plaintext: Vec::new(),
+    };
+
+    // Both sides prove they saw the same keys.
+    let client_hash = digest(key_size, &[&client_key, &server_key]);
+    let server_hash = digest(key_size, &[&server_key, &client_key]);
+    aes_writer.write_all(&client_hash)?;
+    aes_writer.flush()?;
+    let mut received_hash = vec![0; server_hash.len()];
+    aes_reader.read_exact(&mut received_hash)?;
+    if received_hash != server_hash {
+        return Err(Error::AuthenticationFailure(String::from(
+            "RSA-AES server hash mismatch",
+        )));
+    }
+
+    let username = match aes_reader.read_u8()? {
+        SUBTYPE_USER_PASS => username,
+        SUBTYPE_PASS => "",
+        _ => return Err(Error::Unexpected("RSA-AES subtype")),
+    };
+    let username = &username.as_bytes()[..username.len().min(255)];
+    let password = &password.as_bytes()[..password.len().min(255)];
+    aes_writer.write_u8(username.len() as u8)?;
+    aes_writer.write_all(username)?;
+    aes_writer.write_u8(password.len() as u8)?;
+    aes_writer.write_all(password)?;
+    aes_writer.flush()?;
+
+    if encrypt_session {
+        Ok((Box::new(aes_reader), Box::new(aes_writer)))
+    } else {
+        Ok((aes_reader.reader, aes_writer.writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        connect, digest, encode_key, AesReader, AesWriter, Frames, KeySize, SUBTYPE_USER_PASS,
+    };
+    use crate::vnc::client::{Sink, TlsConfig};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use rand::RngCore;
+    use rsa::{BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+    use std::io::{self, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink for Shared {}
+
+    #[test]
+    fn test_frames_round_trip() {
+        let key = [7; 32];
+        let wire = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut writer = AesWriter {
+            writer: Box::new(wire.clone()),
+            frames: Frames::new(KeySize::Aes256, &key),
+            plaintext: Vec::new(),
+        };
+        writer.write_all(&[1, 2, 3]).unwrap();
+        writer.write_all(&[4]).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(&vec![5; 10000]).unwrap();
+        writer.flush().unwrap();
+
+        let sealed = wire.0.lock().unwrap().clone();
+        // Length, then 4 bytes and a tag; the second message is split.
+        assert_eq!(&sealed[..2], &[0, 4]);
+        assert_eq!(sealed.len(), 3 * (2 + 16) + 4 + 10000);
+
+        let mut reader = AesReader {
+            reader: Box::new(io::Cursor::new(sealed.clone())),
+            frames: Frames::new(KeySize::Aes256, &key),
+            plaintext: Vec::new(),
+            position: 0,
+        };
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(&plaintext[..4], &[1, 2, 3, 4]);
+        assert_eq!(plaintext.len(), 10004);
+
+        // Replaying a frame under the next nonce gives it away.
+        let mut replayed = sealed[..2 + 4 + 16].to_vec();
+        replayed.extend_from_slice(&sealed[..2 + 4 + 16]);
+        let mut reader = AesReader {
+            reader: Box::new(io::Cursor::new(replayed)),
+            frames: Frames::new(KeySize::Aes256, &key),
+            plaintext: Vec::new(),
+            position: 0,
+        };
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    // The server half of the handshake, as TigerVNC goes about it, up to a
+    // successful SecurityResult. Returns the credentials it was given.
+    fn serve(stream: UnixStream, key_size: KeySize) -> (String, String) {
+        let mut rng = rand::thread_rng();
+        let server_private_key = RsaPrivateKey::new(&mut rng, 1024).unwrap();
+        let server_key = encode_key(&server_private_key.to_public_key());
+        let mut stream = stream;
+        stream.write_all(&server_key).unwrap();
+
+        let client_bits = stream.read_u32::<BigEndian>().unwrap();
+        let client_size = (client_bits as usize).div_ceil(8);
+        let mut client_key = client_bits.to_be_bytes().to_vec();
+        client_key.resize(4 + 2 * client_size, 0);
+        stream.read_exact(&mut client_key[4..]).unwrap();
+        let client_public_key = RsaPublicKey::new(
+            BigUint::from_bytes_be(&client_key[4..4 + client_size]),
+            BigUint::from_bytes_be(&client_key[4 + client_size..]),
+        )
+        .unwrap();
+
+        let key_length = match key_size {
+            KeySize::Aes128 => 16,
+            KeySize::Aes256 => 32,
+        };
+        let mut encrypted = vec![0; stream.read_u16::<BigEndian>().unwrap() as usize];
+        stream.read_exact(&mut encrypted).unwrap();
+        let client_random = server_private_key
+            .decrypt(Pkcs1v15Encrypt, &encrypted)
+            .unwrap();
+        assert_eq!(client_random.len(), key_length);
+        let mut server_random = vec![0; key_length];
+        rng.fill_bytes(&mut server_random);
+        let encrypted = client_public_key
+            .encrypt(&mut rng, Pkcs1v15Encrypt, &server_random)
+            .unwrap();
+        stream
+            .write_u16::<BigEndian>(encrypted.len() as u16)
+            .unwrap();
+        stream.write_all(&encrypted).unwrap();
+
+        let client_session_key = digest(key_size, &[&server_random, &client_random]);
+        let server_session_key = digest(key_size, &[&client_random, &server_random]);
+        let mut reader = AesReader {
+            reader: Box::new(stream.try_clone().unwrap()),
+            frames: Frames::new(key_size, &client_session_key[..key_length]),
+            plaintext: Vec::new(),
+            position: 0,
+        };
+        let mut writer = AesWriter {
+            writer: Box::new(stream),
+            frames: Frames::new(key_size, &server_session_key[..key_length]),
+            plaintext: Vec::new(),
+        };
+
+        let client_hash = digest(key_size, &[&client_key, &server_key]);
+        let mut received_hash = vec![0; client_hash.len()];
+        reader.read_exact(&mut received_hash).unwrap();
+        assert_eq!(received_hash, client_hash);
+        writer
+            .write_all(&digest(key_size, &[&server_key, &client_key]))
+            .unwrap();
+        writer.flush().unwrap();
+        writer.write_u8(SUBTYPE_USER_PASS).unwrap();
+        writer.flush().unwrap();
+
+        let mut credentials = Vec::new();
+        for _ in 0..2 {
+            let mut field = vec![0; reader.read_u8().unwrap() as usize];
+            reader.read_exact(&mut field).unwrap();
+            credentials.push(String::from_utf8(field).unwrap());
+        }
+        writer.write_u32::<BigEndian>(0).unwrap();
+        writer.flush().unwrap();
+        let password = credentials.pop().unwrap();
+        (credentials.pop().unwrap(), password)
+    }
+
+    #[test]
+    fn test_handshake() {
+        for key_size in [KeySize::Aes128, KeySize::Aes256] {
+            let (client_end, server_end) = UnixStream::pair().unwrap();
+            let server = thread::spawn(move || serve(server_end, key_size));
+
+            let (mut reader, _writer) = connect(
+                Box::new(client_end.try_clone().unwrap()),
+                Box::new(client_end),
+                &TlsConfig::default(),
+                key_size,
+                true,
+                "user",
+                "secret",
+            )
+            .unwrap();
+            // The SecurityResult already comes encrypted.
+            assert_eq!(reader.read_u32::<BigEndian>().unwrap(), 0);
+            let credentials = server.join().unwrap();
+            assert_eq!(credentials, (String::from("user"), String::from("secret")));
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use crate::vnc::{protocol, Error, Rect, Result};
+use byteorder::ReadBytesExt;
+use std::io::Read;
+
+const FILL: u8 = 0x08;
+const JPEG: u8 = 0x09;
+
+const FILTER_COPY: u8 = 0;
+const FILTER_PALETTE: u8 = 1;
+const FILTER_GRADIENT: u8 = 2;
+
+// Data shorter than this is sent as-is rather than through a zlib stream.
+const MIN_TO_COMPRESS: usize = 12;
+
+fn read_compact_length<R: Read>(reader: &mut R) -> Result<usize> {
+    let mut length = 0;
+    for i in 0..3 {
+        let byte = reader.read_u8()? as usize;
+        if i == 2 {
+            length |= byte << 14;
+        } else {
+            length |= (byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+// Tight sends 32-bit pixels with a 24-bit depth as three bytes (R, G, B),
+// regardless of the shifts and endianness of the pixel format.
+fn is_tpixel24(format: &protocol::PixelFormat) -> bool {
+    format.bits_per_pixel == 32
+        && format.depth == 24
+        && format.true_colour
+        && format.red_max == 255
+        && format.green_max == 255
+        && format.blue_max == 255
+}
+
+fn pixel_value(format: &protocol::PixelFormat, bytes: &[u8]) -> u32 {
+    if format.big_endian {
+        bytes
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    } else {
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+}
+
+fn put_pixel_value(format: &protocol::PixelFormat, value: u32, pixels: &mut Vec<u8>) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    for i in 0..bpp {
+        let shift = if format.big_endian { bpp - 1 - i } else { i } * 8;
+        pixels.push((value >> shift) as u8);
+    }
+}
+
+fn components(format: &protocol::PixelFormat) -> [(u32, u8); 3] {
+    [
+        (format.red_max as u32, format.red_shift),
+        (format.green_max as u32, format.green_shift),
+        (format.blue_max as u32, format.blue_shift),
+    ]
+}
+
+pub struct Decoder {
+    streams: [Option<flate2::Decompress>; 4],
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            streams: [None, None, None, None],
+        }
+    }
+
+    pub fn decode<R, P, F>(
+        &mut self,
+        format: protocol::PixelFormat,
+        rect: Rect,
+        reader: &mut R,
+        mut put_pixels: P,
+        mut fill_pixels: F,
+    ) -> Result<bool>
+    where
+        R: Read,
+        P: FnMut(Rect, Vec<u8>) -> Result<bool>,
+        F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+    {
+        let bpp = format.bits_per_pixel as usize / 8;
+        let tpixel24 = is_tpixel24(&format);
+        let tpixel_size = if tpixel24 { 3 } else { bpp };
+        let pixel_count = rect.width as usize * rect.height as usize;
+
+        // Converts a run of TPIXELs to pixels in the negotiated format.
+        let to_pixels = |tpixels: &[u8]| -> Vec<u8> {
+            if tpixel24 {
+                let mut pixels = Vec::with_capacity(tpixels.len() / 3 * bpp);
+                for rgb in tpixels.chunks_exact(3) {
+                    let value = (rgb[0] as u32) << format.red_shift
+                        | (rgb[1] as u32) << format.green_shift
+                        | (rgb[2] as u32) << format.blue_shift;
+                    put_pixel_value(&format, value, &mut pixels);
+                }
+                pixels
+            } else {
+                tpixels.to_vec()
+            }
+        };
+
+        let control = reader.read_u8()?;
+        for (i, stream) in self.streams.iter_mut().enumerate() {
+            if control & (1 << i) != 0 {
+                *stream = None;
+            }
+        }
+
+        match control >> 4 {
+            FILL => {
+                let mut tpixel = vec![0; tpixel_size];
+                reader.read_exact(&mut tpixel)?;
+                fill_pixels(rect, to_pixels(&tpixel))
+            }
+            JPEG => {
+                let length = read_compact_length(reader)?;
+                let mut data = vec![0; length];
+                reader.read_exact(&mut data)?;
+                let luminance = decode_jpeg_luminance(&data, rect)?;
+
+                // The output is grayscale anyway, so there's no point in
+                // converting the chroma; map luminance straight to pixels.
+                let mut gray_pixels = Vec::with_capacity(256 * bpp);
+                for gray in 0..=255u32 {
+                    let value = components(&format).iter().fold(0, |value, &(max, shift)| {
+                        value | (gray * max / 255) << shift
+                    });
+                    put_pixel_value(&format, value, &mut gray_pixels);
+                }
+                let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                for &gray in &luminance {
+                    let start = gray as usize * bpp;
+                    pixels.extend_from_slice(&gray_pixels[start..start + bpp]);
+                }
+                put_pixels(rect, pixels)
+            }
+            basic if basic & 0x08 == 0 => {
+                let stream_id = (basic & 0x03) as usize;
+                let filter = if basic & 0x04 != 0 {
+                    reader.read_u8()?
+                } else {
+                    FILTER_COPY
+                };
+
+                match filter {
+                    FILTER_COPY => {
+                        let data = self.read_data(reader, stream_id, pixel_count * tpixel_size)?;
+                        put_pixels(rect, to_pixels(&data))
+                    }
+                    FILTER_PALETTE => {
+                        let palette_size = reader.read_u8()? as usize + 1;
+                        let mut tpixels = vec![0; palette_size * tpixel_size];
+                        reader.read_exact(&mut tpixels)?;
+                        let palette = to_pixels(&tpixels);
+                        // Nothing to draw, and no rows to split the data into.
+                        if rect.width == 0 || rect.height == 0 {
+                            return Ok(true);
+                        }
+
+                        let row_size = if palette_size == 2 {
+                            (rect.width as usize).div_ceil(8)
+                        } else {
+                            rect.width as usize
+                        };
+                        let data =
+                            self.read_data(reader, stream_id, row_size * rect.height as usize)?;
+
+                        let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                        for row in data.chunks_exact(row_size) {
+                            for x in 0..rect.width as usize {
+                                let index = if palette_size == 2 {
+                                    (row[x / 8] >> (7 - x % 8)) as usize & 1
+                                } else {
+                                    row[x] as usize
+                                };
+                                if index >= palette_size {
+                                    return Err(Error::Unexpected("Tight palette index"));
+                                }
+                                let start = index * bpp;
+                                pixels.extend_from_slice(&palette[start..start + bpp]);
+                            }
+                        }
+                        put_pixels(rect, pixels)
+                    }
+                    FILTER_GRADIENT => {
+                        if !format.true_colour {
+                            return Err(Error::Unexpected("Tight gradient filter"));
+                        }
+                        let data = self.read_data(reader, stream_id, pixel_count * tpixel_size)?;
+                        let components = components(&format);
+                        let diffs: Vec<[u32; 3]> = if tpixel24 {
+                            data.chunks_exact(3)
+                                .map(|rgb| [rgb[0] as u32, rgb[1] as u32, rgb[2] as u32])
+                                .collect()
+                        } else {
+                            data.chunks_exact(bpp)
+                                .map(|bytes| {
+                                    let value = pixel_value(&format, bytes);
+                                    components.map(|(max, shift)| (value >> shift) & max)
+                                })
+                                .collect()
+                        };
+
+                        let maxes = components.map(|(max, _)| max);
+                        let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                        for rgb in undo_gradient(&diffs, rect.width as usize, maxes) {
+                            let value = components
+                                .iter()
+                                .zip(rgb.iter())
+                                .fold(0, |value, (&(_, shift), &c)| value | c << shift);
+                            put_pixel_value(&format, value, &mut pixels);
+                        }
+                        put_pixels(rect, pixels)
+                    }
+                    _ => Err(Error::Unexpected("Tight filter")),
+                }
+            }
+            _ => Err(Error::Unexpected("Tight compression control")),
+        }
+    }
+
+    fn read_data<R: Read>(
+        &mut self,
+        reader: &mut R,
+        stream_id: usize,
+        size: usize,
+    ) -> Result<Vec<u8>> {
+        if size < MIN_TO_COMPRESS {
+            let mut data = vec![0; size];
+            reader.read_exact(&mut data)?;
+            return Ok(data);
+        }
+
+        let length = read_compact_length(reader)?;
+        let mut input = vec![0; length];
+        reader.read_exact(&mut input)?;
+
+        let stream = self.streams[stream_id]
+            .get_or_insert_with(|| flate2::Decompress::new(/*zlib_header*/ true));
+
+        // Leave a byte of slack so that the decompressor also consumes the
+        // trailing sync flush marker, which produces no output.
+        let mut output = vec![0; size + 1];
+        let mut produced = 0;
+        let mut input = &input[..];
+        loop {
+            let in_before = stream.total_in();
+            let out_before = stream.total_out();
+            stream
+                .decompress(
+                    input,
+                    &mut output[produced..],
+                    flate2::FlushDecompress::Sync,
+                )
+                .map_err(|_| Error::Unexpected("Tight zlib data"))?;
+            let consumed = (stream.total_in() - in_before) as usize;
+            produced += (stream.total_out() - out_before) as usize;
+            input = &input[consumed..];
+            if input.is_empty() || consumed == 0 {
+                break;
+            }
+        }
+
+        if produced != size || !input.is_empty() {
+            return Err(Error::Unexpected("Tight zlib data length"));
+        }
+        output.truncate(size);
+        Ok(output)
+    }
+}
+
+// Reverses the gradient filter: every component was sent as the difference
+// from the prediction `left + above - above_left`, clamped to its range.
+fn undo_gradient(diffs: &[[u32; 3]], width: usize, maxes: [u32; 3]) -> Vec<[u32; 3]> {
+    let mut values: Vec<[u32; 3]> = Vec::with_capacity(diffs.len());
+    for (i, diff) in diffs.iter().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let left = if x > 0 { values[i - 1] } else { [0; 3] };
+        let above = if y > 0 { values[i - width] } else { [0; 3] };
+        let above_left = if x > 0 && y > 0 {
+            values[i - width - 1]
+        } else {
+            [0; 3]
+        };
+
+        let mut rgb = [0; 3];
+        for c in 0..3 {
+            let prediction = (left[c] as i32 + above[c] as i32 - above_left[c] as i32)
+                .clamp(0, maxes[c] as i32) as u32;
+            rgb[c] = (prediction + diff[c]) & maxes[c];
+        }
+        values.push(rgb);
+    }
+    values
+}
+
+// Decodes a JPEG rectangle to one luminance byte per pixel. The colour
+// transform is skipped so YCbCr images come out with Y as their first channel.
+fn decode_jpeg_luminance(data: &[u8], rect: Rect) -> Result<Vec<u8>> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.set_color_transform(jpeg_decoder::ColorTransform::None);
+    let output = decoder
+        .decode()
+        .map_err(|_| Error::Unexpected("Tight JPEG data"))?;
+    let info = decoder.info().ok_or(Error::Unexpected("Tight JPEG data"))?;
+    if info.width != rect.width || info.height != rect.height {
+        return Err(Error::Unexpected("Tight JPEG size"));
+    }
+
+    let channels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => 1,
+        jpeg_decoder::PixelFormat::RGB24 => 3,
+        _ => return Err(Error::Unexpected("Tight JPEG pixel format")),
+    };
+    Ok(output.iter().step_by(channels).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decoder;
+    use crate::vnc::{protocol::PixelFormat, Rect};
+    use std::io::Write;
+
+    const FORMAT: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    const RECT: Rect = Rect {
+        left: 0,
+        top: 0,
+        width: 4,
+        height: 2,
+    };
+
+    // The pixels put and the ones filled, each with its rectangle.
+    type Decoded = (Vec<(Rect, Vec<u8>)>, Vec<(Rect, Vec<u8>)>);
+
+    fn decode(decoder: &mut Decoder, input: &[u8]) -> Decoded {
+        decode_rect(decoder, RECT, input)
+    }
+
+    fn decode_rect(decoder: &mut Decoder, rect: Rect, input: &[u8]) -> Decoded {
+        let mut puts = Vec::new();
+        let mut fills = Vec::new();
+        decoder
+            .decode(
+                FORMAT,
+                rect,
+                &mut &input[..],
+                |rect, pixels| {
+                    puts.push((rect, pixels));
+                    Ok(true)
+                },
+                |rect, pixel| {
+                    fills.push((rect, pixel));
+                    Ok(true)
+                },
+            )
+            .unwrap();
+        (puts, fills)
+    }
+
+    #[test]
+    fn test_decode_fill() {
+        let (puts, fills) = decode(&mut Decoder::new(), &[0x80, 0x11, 0x22, 0x33]);
+        assert!(puts.is_empty());
+        assert_eq!(fills, vec![(RECT, vec![0x33, 0x22, 0x11, 0x00])]);
+    }
+
+    #[test]
+    fn test_decode_mono_palette() {
+        // Explicit filter on stream 1, two colour palette, 1 byte per row.
+        let input = [
+            0x50,
+            0x01,
+            0x01,
+            0,
+            0,
+            0,
+            0xff,
+            0xff,
+            0xff,
+            0b1010_0000,
+            0b0101_0000,
+        ];
+        let (puts, _) = decode(&mut Decoder::new(), &input);
+        let (b, w) = ([0, 0, 0, 0], [0xff, 0xff, 0xff, 0]);
+        let expected: Vec<u8> = [w, b, w, b, b, w, b, w].concat();
+        assert_eq!(puts, vec![(RECT, expected)]);
+    }
+
+    #[test]
+    fn test_decode_empty_palette_rect() {
+        // A two colour palette for a rectangle no pixel wide.
+        let input = [0x40, 0x01, 0x01, 0, 0, 0, 0xff, 0xff, 0xff];
+        let rect = Rect { width: 0, ..RECT };
+        let (puts, fills) = decode_rect(&mut Decoder::new(), rect, &input);
+        assert!(puts.is_empty());
+        assert!(fills.is_empty());
+    }
+
+    #[test]
+    fn test_decode_compressed_gradient() {
+        // A horizontal ramp 10, 20, 30, 40 in every channel, on both rows.
+        let mut diffs = Vec::new();
+        diffs.extend_from_slice(&[10; 12]);
+        diffs.extend_from_slice(&[0; 12]);
+
+        let mut compressor =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        compressor.write_all(&diffs).unwrap();
+        compressor.flush().unwrap();
+        let compressed = compressor.get_ref().clone();
+
+        let mut input = vec![0x40, 0x02, compressed.len() as u8];
+        input.extend_from_slice(&compressed);
+        let (puts, _) = decode(&mut Decoder::new(), &input);
+
+        let mut expected = Vec::new();
+        for _ in 0..2 {
+            for v in [10, 20, 30, 40] {
+                expected.extend_from_slice(&[v, v, v, 0]);
+            }
+        }
+        assert_eq!(puts, vec![(RECT, expected)]);
+    }
+}
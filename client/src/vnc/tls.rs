@@ -0,0 +1,209 @@
+use crate::vnc::client::{Sink, TlsConfig};
+use crate::vnc::known_hosts;
+use crate::vnc::protocol::{self, Message};
+use crate::vnc::{Error, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, SslConnector, SslMethod, SslStream, SslVerifyMode, SslVersion,
+};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// The raw side of a TLS session: the ciphertext the reading half has
+// received so far, and the writing half of the connection.
+struct Channel {
+    incoming: VecDeque<u8>,
+    writer: Box<dyn Sink>,
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            // Makes OpenSSL ask for more, which the reading half provides.
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.incoming.read(buf)
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+type Session = Arc<Mutex<SslStream<Channel>>>;
+
+// The two halves of a connection share one TLS session. The reading half
+// never holds on to it while waiting for the network, so writes aren't
+// held up by a read that has nothing to read yet.
+pub struct TlsReader {
+    reader: Box<dyn Read + Send>,
+    session: Session,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.lock().unwrap().ssl_read(buf) {
+                Ok(count) => return Ok(count),
+                Err(ref error) if error.code() == ErrorCode::WANT_READ => (),
+                Err(ref error) if error.code() == ErrorCode::ZERO_RETURN => return Ok(0),
+                Err(error) => return Err(io::Error::other(error)),
+            }
+
+            let mut ciphertext = [0; 16384];
+            let count = self.reader.read(&mut ciphertext)?;
+            if count == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            session.get_mut().incoming.extend(&ciphertext[..count]);
+        }
+    }
+}
+
+pub struct TlsWriter {
+    session: Session,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session
+            .lock()
+            .unwrap()
+            .ssl_write(buf)
+            .map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.lock().unwrap().get_mut().flush()
+    }
+}
+
+impl Sink for TlsWriter {
+    fn close(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        let _ = session.shutdown();
+        session.get_mut().writer.close()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // Anonymous Diffie-Hellman, as in the TLS security type and the VeNCrypt
+    // TLS subtypes. Encrypted, but nothing proves who's at the other end.
+    Anonymous,
+    // A server certificate, checked against the configured CA or else the
+    // fingerprint remembered from the first connection.
+    X509,
+}
+
+pub fn connect(
+    mut reader: Box<dyn Read + Send>,
+    writer: Box<dyn Sink>,
+    config: &TlsConfig,
+    mode: Mode,
+) -> Result<(TlsReader, TlsWriter)> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(tls_error)?;
+    match mode {
+        Mode::Anonymous => {
+            builder.set_verify(SslVerifyMode::NONE);
+            // There's no anonymous key exchange in TLS 1.3.
+            builder
+                .set_max_proto_version(Some(SslVersion::TLS1_2))
+                .map_err(tls_error)?;
+            builder
+                .set_cipher_list("aNULL:!eNULL:@SECLEVEL=0")
+                .map_err(tls_error)?;
+        }
+        Mode::X509 => match config.ca_file {
+            Some(ref ca_file) => builder.set_ca_file(ca_file).map_err(tls_error)?,
+            None => builder.set_verify(SslVerifyMode::NONE),
+        },
+    }
+    let verify_name = mode == Mode::X509 && config.ca_file.is_some();
+    let channel = Channel {
+        incoming: VecDeque::new(),
+        writer,
+    };
+    let mut handshake = builder
+        .build()
+        .configure()
+        .map_err(tls_error)?
+        .verify_hostname(verify_name)
+        .use_server_name_indication(verify_name)
+        .connect(&config.host, channel);
+
+    let stream = loop {
+        match handshake {
+            Ok(stream) => break stream,
+            Err(HandshakeError::WouldBlock(mut stream)) => {
+                let mut ciphertext = [0; 16384];
+                let count = reader.read(&mut ciphertext)?;
+                if count == 0 {
+                    return Err(Error::Disconnected);
+                }
+                stream.get_mut().incoming.extend(&ciphertext[..count]);
+                handshake = stream.handshake();
+            }
+            Err(HandshakeError::Failure(stream)) => {
+                return Err(Error::Tls(stream.error().to_string()))
+            }
+            Err(HandshakeError::SetupFailure(error)) => return Err(tls_error(error)),
+        }
+    };
+
+    if mode == Mode::X509 && config.ca_file.is_none() {
+        let certificate = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or(Error::Tls(String::from("no server certificate")))?;
+        let digest = certificate
+            .digest(MessageDigest::sha256())
+            .map_err(tls_error)?;
+        known_hosts::check(config, "x509", &known_hosts::fingerprint(&digest))?;
+    }
+
+    let session = Arc::new(Mutex::new(stream));
+    Ok((
+        TlsReader {
+            reader,
+            session: session.clone(),
+        },
+        TlsWriter { session },
+    ))
+}
+
+// Settles the VeNCrypt version and returns the subtypes the server offers.
+pub fn vencrypt_subtypes<S: Read + Write>(stream: &mut S) -> Result<Vec<protocol::VeNCryptSubtype>> {
+    let major = stream.read_u8()?;
+    let minor = stream.read_u8()?;
+    debug!("<- VeNCrypt {}.{}", major, minor);
+    if (major, minor) < (0, 2) {
+        return Err(Error::Unexpected("VeNCrypt version"));
+    }
+    stream.write_u8(0)?;
+    stream.write_u8(2)?;
+    if stream.read_u8()? != 0 {
+        return Err(Error::Server(String::from("VeNCrypt version 0.2 refused")));
+    }
+
+    let count = stream.read_u8()?;
+    let mut subtypes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        subtypes.push(protocol::VeNCryptSubtype::read_from(stream)?);
+    }
+    debug!("<- {:?}", subtypes);
+    Ok(subtypes)
+}
+
+fn tls_error<E: std::fmt::Display>(error: E) -> Error {
+    Error::Tls(error.to_string())
+}
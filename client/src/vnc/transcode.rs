@@ -0,0 +1,326 @@
+use crate::vnc::client::{DesktopSizeError, Event};
+use crate::vnc::gray::{GrayConverter, LumaWeights, GRAY8_FORMAT};
+use crate::vnc::protocol::{self, Colour, Encoding};
+use crate::vnc::{Error, Rect, Result};
+
+// What the proxy does to frames before they go to an e-ink reader: gray
+// levels through the contrast curve, then down to the 16 the panel shows.
+#[derive(Debug, Clone)]
+pub struct Transcode {
+    pub luma: LumaWeights,
+    pub curve: [u8; 256],
+    pub dither: bool,
+}
+
+// Thresholds of an ordered dither, in 1/16ths of a step between levels.
+const BAYER: [[u32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// Rounds to a multiple of 17. Dithering spreads the remainder over
+// neighbouring pixels; levels that are already exact stay untouched.
+fn quantize(value: u8, x: usize, y: usize, dither: bool) -> u8 {
+    let threshold = if dither {
+        (BAYER[y % 4][x % 4] * 2 + 1) * 255 / 32
+    } else {
+        127
+    };
+    ((value as u32 * 15 + threshold) / 255 * 17) as u8
+}
+
+// The proxy's gray pixels as a format with the same level in all three
+// channels, for translating them to whatever a viewer asks for.
+const GRAY: protocol::PixelFormat = protocol::PixelFormat {
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    ..GRAY8_FORMAT
+};
+
+// What an event from the server changed, for every viewer to catch up on
+// when it next asks for an update.
+pub(super) enum Change {
+    Pixels(Rect),
+    Copy {
+        src: Rect,
+        dst: Rect,
+    },
+    ColourMap {
+        first_colour: u16,
+        colours: Vec<Colour>,
+    },
+    Cursor,
+    Pointer(u16, u16),
+    Resize,
+    Layout,
+    DesktopSizeRejected(u16),
+    Clipboard(String),
+    Bell,
+    EndOfFrame,
+}
+
+// The proxy's own copy of the server's framebuffer, which every viewer is
+// sent its updates from. Transcoded, it holds the gray levels the panel
+// shows; otherwise, the server's pixels as they are.
+pub(super) struct Desktop {
+    transcode: Option<(Transcode, GrayConverter)>,
+    server_format: protocol::PixelFormat,
+    pub(super) size: (u16, u16),
+    pixels: Vec<u8>,
+    pub(super) screens: Vec<protocol::Screen>,
+    pub(super) colour_map: Vec<Colour>,
+    // The last cursor shape, already in the format of `pixels`.
+    pub(super) cursor: Option<(protocol::Rectangle, Vec<u8>, Vec<u8>)>,
+    // Whether the server has sent a whole frame yet.
+    pub(super) ready: bool,
+}
+
+impl Desktop {
+    pub(super) fn new(
+        server_format: protocol::PixelFormat,
+        size: (u16, u16),
+        transcode: Option<Transcode>,
+    ) -> Desktop {
+        let mut desktop = Desktop {
+            transcode: transcode.map(|transcode| {
+                let gray = GrayConverter::new(server_format, transcode.luma);
+                (transcode, gray)
+            }),
+            server_format,
+            size,
+            pixels: Vec::new(),
+            screens: Vec::new(),
+            colour_map: Vec::new(),
+            cursor: None,
+            ready: false,
+        };
+        desktop.resize(size);
+        desktop
+    }
+
+    // What viewers are told the pixel format is when they connect.
+    pub(super) fn format(&self) -> protocol::PixelFormat {
+        match self.transcode {
+            Some(_) => GRAY8_FORMAT,
+            None => self.server_format,
+        }
+    }
+
+    // What `pixels` are in, for translating them to a viewer's format.
+    pub(super) fn source_format(&self) -> protocol::PixelFormat {
+        match self.transcode {
+            Some(_) => GRAY,
+            None => self.server_format,
+        }
+    }
+
+    fn bpp(&self) -> usize {
+        self.source_format().bits_per_pixel as usize / 8
+    }
+
+    pub(super) fn handle(&mut self, event: Event) -> Result<Option<Change>> {
+        let change = match event {
+            Event::Disconnected(None) => return Err(Error::Disconnected),
+            Event::Disconnected(Some(error)) => return Err(error),
+            Event::SetColourMap {
+                first_colour,
+                colours,
+            } => {
+                if let Some((_, ref mut gray)) = self.transcode {
+                    gray.set_colour_map(first_colour, &colours);
+                    return Ok(None);
+                }
+                let end = first_colour as usize + colours.len();
+                if self.colour_map.len() < end {
+                    self.colour_map.resize(
+                        end,
+                        Colour {
+                            red: 0,
+                            green: 0,
+                            blue: 0,
+                        },
+                    );
+                }
+                self.colour_map[first_colour as usize..end].copy_from_slice(&colours);
+                Change::ColourMap {
+                    first_colour,
+                    colours,
+                }
+            }
+            Event::PutPixels(rect, pixels) => {
+                let pixels = match self.transcode {
+                    Some((_, ref gray)) => gray.convert(&pixels),
+                    None => pixels,
+                };
+                self.draw(rect, &pixels)?;
+                Change::Pixels(rect)
+            }
+            Event::FillPixels(rect, pixel) => {
+                let pixel = match self.transcode {
+                    Some((_, ref gray)) => vec![gray.pixel(&pixel)],
+                    None => pixel,
+                };
+                self.draw(
+                    rect,
+                    &pixel.repeat(rect.width as usize * rect.height as usize),
+                )?;
+                Change::Pixels(rect)
+            }
+            Event::CopyPixels { src, dst } => {
+                self.copy(src, dst)?;
+                Change::Copy { src, dst }
+            }
+            Event::SetCursor {
+                size,
+                hotspot,
+                pixels,
+                mask_bits,
+            } => {
+                let pixels = match self.transcode {
+                    Some((ref options, ref gray)) => gray
+                        .convert(&pixels)
+                        .into_iter()
+                        .map(|c| quantize(options.curve[c as usize], 0, 0, false))
+                        .collect(),
+                    None => pixels,
+                };
+                let rectangle = protocol::Rectangle {
+                    x_position: hotspot.0,
+                    y_position: hotspot.1,
+                    width: size.0,
+                    height: size.1,
+                    encoding: Encoding::Cursor,
+                };
+                self.cursor = Some((rectangle, pixels, mask_bits));
+                Change::Cursor
+            }
+            Event::CursorPosition(x, y) => Change::Pointer(x, y),
+            Event::Resize(width, height) => {
+                self.resize((width, height));
+                Change::Resize
+            }
+            Event::DesktopLayout { size, screens } => {
+                self.screens = screens;
+                if size != self.size {
+                    self.resize(size);
+                    Change::Resize
+                } else {
+                    Change::Layout
+                }
+            }
+            Event::DesktopSizeRejected(error) => Change::DesktopSizeRejected(match error {
+                DesktopSizeError::Prohibited => 1,
+                DesktopSizeError::OutOfResources => 2,
+                DesktopSizeError::InvalidLayout => 3,
+                DesktopSizeError::Unknown(status) => status,
+            }),
+            Event::EndOfFrame => {
+                self.ready = true;
+                Change::EndOfFrame
+            }
+            Event::Clipboard(text) => Change::Clipboard(text),
+            Event::Bell => Change::Bell,
+            // Never asked of the server.
+            Event::EndOfContinuousUpdates | Event::Fence { .. } => return Ok(None),
+        };
+        Ok(Some(change))
+    }
+
+    fn check_bounds(&self, rect: Rect) -> Result<()> {
+        if rect.left as u32 + rect.width as u32 > self.size.0 as u32
+            || rect.top as u32 + rect.height as u32 > self.size.1 as u32
+        {
+            return Err(Error::Unexpected("rectangle outside the framebuffer"));
+        }
+        Ok(())
+    }
+
+    // Gray levels, if transcoding, go through the contrast curve and get
+    // dithered on the way in.
+    fn draw(&mut self, rect: Rect, pixels: &[u8]) -> Result<()> {
+        self.check_bounds(rect)?;
+        let bpp = self.bpp();
+        let width = rect.width as usize * bpp;
+        if pixels.len() != width * rect.height as usize {
+            return Err(Error::Unexpected("pixel count"));
+        }
+        let stride = self.size.0 as usize * bpp;
+        for (row, line) in pixels.chunks(width.max(1)).enumerate() {
+            let y = rect.top as usize + row;
+            let start = y * stride + rect.left as usize * bpp;
+            let target = &mut self.pixels[start..start + width];
+            match self.transcode {
+                Some((ref options, _)) => {
+                    for (x, (pixel, &gray)) in target.iter_mut().zip(line).enumerate() {
+                        *pixel = quantize(
+                            options.curve[gray as usize],
+                            rect.left as usize + x,
+                            y,
+                            options.dither,
+                        );
+                    }
+                }
+                None => target.copy_from_slice(line),
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, src: Rect, dst: Rect) -> Result<()> {
+        self.check_bounds(src)?;
+        self.check_bounds(dst)?;
+        let bpp = self.bpp();
+        let stride = self.size.0 as usize * bpp;
+        let rows: Vec<usize> = if dst.top > src.top {
+            (0..dst.height as usize).rev().collect()
+        } else {
+            (0..dst.height as usize).collect()
+        };
+        for row in rows {
+            let from = (src.top as usize + row) * stride + src.left as usize * bpp;
+            let to = (dst.top as usize + row) * stride + dst.left as usize * bpp;
+            self.pixels
+                .copy_within(from..from + dst.width as usize * bpp, to);
+        }
+        Ok(())
+    }
+
+    fn resize(&mut self, size: (u16, u16)) {
+        self.size = size;
+        self.pixels = vec![0; size.0 as usize * size.1 as usize * self.bpp()];
+    }
+
+    // The pixels of an area, row after row.
+    pub(super) fn pixels(&self, rect: Rect) -> Vec<u8> {
+        let bpp = self.bpp();
+        let stride = self.size.0 as usize * bpp;
+        let width = rect.width as usize * bpp;
+        let mut pixels = Vec::with_capacity(width * rect.height as usize);
+        for y in rect.top as usize..(rect.top + rect.height) as usize {
+            let start = y * stride + rect.left as usize * bpp;
+            pixels.extend_from_slice(&self.pixels[start..start + width]);
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quantize;
+
+    #[test]
+    fn test_quantize() {
+        for level in 0..16u8 {
+            for y in 0..4 {
+                for x in 0..4 {
+                    assert_eq!(quantize(level * 17, x, y, true), level * 17);
+                }
+            }
+        }
+        assert_eq!(quantize(8, 0, 0, false), 0);
+        assert_eq!(quantize(9, 0, 0, false), 17);
+
+        // Halfway between two levels, half of the pixels go either way.
+        let dithered: Vec<u8> = (0..16).map(|i| quantize(144, i % 4, i / 4, true)).collect();
+        assert_eq!(dithered.iter().filter(|&&v| v == 136).count(), 8);
+        assert_eq!(dithered.iter().filter(|&&v| v == 153).count(), 8);
+    }
+}
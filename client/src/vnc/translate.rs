@@ -0,0 +1,229 @@
+use crate::vnc::protocol::{Colour, PixelFormat};
+
+// Clients asking for indexed pixels get this colour cube, three bits of red
+// and green and two of blue, as their colour map.
+fn bgr233(format: PixelFormat) -> PixelFormat {
+    PixelFormat {
+        true_colour: true,
+        red_max: 7,
+        green_max: 7,
+        blue_max: 3,
+        red_shift: 0,
+        green_shift: 3,
+        blue_shift: 6,
+        ..format
+    }
+}
+
+fn read_pixel(format: &PixelFormat, bytes: &[u8]) -> u32 {
+    if format.big_endian {
+        bytes
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    } else {
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+}
+
+fn write_pixel(format: &PixelFormat, value: u32, output: &mut Vec<u8>) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    if format.big_endian {
+        output.extend_from_slice(&value.to_be_bytes()[4 - bpp..])
+    } else {
+        output.extend_from_slice(&value.to_le_bytes()[..bpp])
+    }
+}
+
+// Converts pixels from the format the server sends into the one a client
+// asked for, so that the server never has to switch formats mid-stream.
+pub struct Translator {
+    from: PixelFormat,
+    to: PixelFormat,
+    indexed: bool,
+    colour_map: Vec<Colour>,
+}
+
+impl Translator {
+    pub fn new(from: PixelFormat, to: PixelFormat) -> Translator {
+        Translator {
+            from,
+            to: if to.true_colour { to } else { bgr233(to) },
+            indexed: !to.true_colour,
+            colour_map: Vec::new(),
+        }
+    }
+
+    pub fn set_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        let first = first_colour as usize;
+        if self.colour_map.len() < first + colours.len() {
+            self.colour_map.resize(
+                first + colours.len(),
+                Colour {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                },
+            );
+        }
+        self.colour_map[first..first + colours.len()].copy_from_slice(colours);
+    }
+
+    // What the client has to be told its colour map is, if it asked for
+    // indexed pixels.
+    pub fn client_colour_map(&self) -> Option<Vec<Colour>> {
+        if !self.indexed {
+            return None;
+        }
+        let scale = |value: u32, max: u32| (value * 65535 / max) as u16;
+        Some(
+            (0..256)
+                .map(|index| Colour {
+                    red: scale(index & 7, 7),
+                    green: scale(index >> 3 & 7, 7),
+                    blue: scale(index >> 6, 3),
+                })
+                .collect(),
+        )
+    }
+
+    // The 16-bit components of a pixel.
+    fn colour(&self, value: u32) -> Colour {
+        let from = &self.from;
+        if !from.true_colour {
+            // Entries the server never set stay black.
+            return self
+                .colour_map
+                .get(value as usize)
+                .copied()
+                .unwrap_or(Colour {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                });
+        }
+        let channel = |max: u16, shift: u8| {
+            if max == 0 {
+                0
+            } else {
+                ((value >> shift & max as u32) * 65535 / max as u32) as u16
+            }
+        };
+        Colour {
+            red: channel(from.red_max, from.red_shift),
+            green: channel(from.green_max, from.green_shift),
+            blue: channel(from.blue_max, from.blue_shift),
+        }
+    }
+
+    pub fn translate(&self, pixels: &[u8]) -> Vec<u8> {
+        let from_bpp = self.from.bits_per_pixel as usize / 8;
+        let to = &self.to;
+        let channel = |component: u16, max: u16, shift: u8| {
+            ((component as u32 * max as u32 + 32767) / 65535) << shift
+        };
+
+        let mut output =
+            Vec::with_capacity(pixels.len() / from_bpp * to.bits_per_pixel as usize / 8);
+        for pixel in pixels.chunks_exact(from_bpp) {
+            let colour = self.colour(read_pixel(&self.from, pixel));
+            let value = channel(colour.red, to.red_max, to.red_shift)
+                | channel(colour.green, to.green_max, to.green_shift)
+                | channel(colour.blue, to.blue_max, to.blue_shift);
+            write_pixel(to, value, &mut output);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Translator;
+    use crate::vnc::protocol::{Colour, PixelFormat};
+
+    const BGRX: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    #[test]
+    fn test_translate_true_colour() {
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        let translator = Translator::new(BGRX, rgb565);
+        assert_eq!(translator.client_colour_map(), None);
+        let pixels = [0xff, 0xff, 0xff, 0, 0, 0, 0xff, 0, 0xff, 0, 0, 0];
+        assert_eq!(
+            translator.translate(&pixels),
+            vec![0xff, 0xff, 0xf8, 0x00, 0x00, 0x1f]
+        );
+
+        let back = Translator::new(rgb565, BGRX);
+        assert_eq!(back.translate(&[0xf8, 0x00]), vec![0, 0, 0xff, 0]);
+    }
+
+    #[test]
+    fn test_translate_indexed() {
+        let indexed = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian: false,
+            true_colour: false,
+            red_max: 0,
+            green_max: 0,
+            blue_max: 0,
+            red_shift: 0,
+            green_shift: 0,
+            blue_shift: 0,
+        };
+
+        // Indexed pixels from the server go through its colour map...
+        let mut from_server = Translator::new(indexed, BGRX);
+        from_server.set_colour_map(
+            1,
+            &[Colour {
+                red: 0xffff,
+                green: 0x8080,
+                blue: 0,
+            }],
+        );
+        assert_eq!(
+            from_server.translate(&[1, 2]),
+            vec![0, 0x80, 0xff, 0, 0, 0, 0, 0]
+        );
+
+        // ...and indexed pixels for the client into the colour cube.
+        let to_client = Translator::new(BGRX, indexed);
+        let colour_map = to_client.client_colour_map().unwrap();
+        let translated = to_client.translate(&[0xff, 0, 0xff, 0, 0, 0xff, 0, 0]);
+        assert_eq!(translated, vec![0b11_000_111, 0b00_111_000]);
+        assert_eq!(
+            colour_map[translated[0] as usize],
+            Colour {
+                red: 0xffff,
+                green: 0,
+                blue: 0xffff,
+            }
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use crate::vnc::protocol::{self, Encoding, Message};
+use crate::vnc::transcode::{Change, Desktop};
+use crate::vnc::translate::Translator;
+use crate::vnc::{zrle, Error, Rect, Result};
+use std::io::Write;
+use std::sync::mpsc::Sender;
+
+// Beyond this many damaged areas a frame goes out as their bounding box,
+// and beyond this many copies they go out as pixels.
+const MAX_DAMAGE: usize = 64;
+
+fn touches(a: &Rect, b: &Rect) -> bool {
+    a.left as u32 <= b.left as u32 + b.width as u32
+        && b.left as u32 <= a.left as u32 + a.width as u32
+        && a.top as u32 <= b.top as u32 + b.height as u32
+        && b.top as u32 <= a.top as u32 + a.height as u32
+}
+
+fn intersects(a: &Rect, b: &Rect) -> bool {
+    (a.left as u32) < b.left as u32 + b.width as u32
+        && (b.left as u32) < a.left as u32 + a.width as u32
+        && (a.top as u32) < b.top as u32 + b.height as u32
+        && (b.top as u32) < a.top as u32 + a.height as u32
+}
+
+fn union(a: &Rect, b: &Rect) -> Rect {
+    let left = a.left.min(b.left);
+    let top = a.top.min(b.top);
+    Rect {
+        left,
+        top,
+        width: (a.left + a.width).max(b.left + b.width) - left,
+        height: (a.top + a.height).max(b.top + b.height) - top,
+    }
+}
+
+// The part of a rectangle inside a framebuffer of the given size.
+fn clip(rect: Rect, size: (u16, u16)) -> Rect {
+    let left = rect.left.min(size.0);
+    let top = rect.top.min(size.1);
+    Rect {
+        left,
+        top,
+        width: rect.width.min(size.0 - left),
+        height: rect.height.min(size.1 - top),
+    }
+}
+
+fn pseudo_rect(
+    update: &mut Vec<u8>,
+    position: (u16, u16),
+    size: (u16, u16),
+    encoding: Encoding,
+) -> Result<()> {
+    protocol::Rectangle {
+        x_position: position.0,
+        y_position: position.1,
+        width: size.0,
+        height: size.1,
+        encoding,
+    }
+    .write_to(update)
+}
+
+// One of the clients sharing the proxy's server connection. Whatever
+// changes on the desktop piles up until the viewer asks for an update, which
+// then goes out in the encodings and the pixel format it asked for.
+pub(super) struct Viewer {
+    // Messages for the thread writing to the viewer.
+    messages: Sender<Vec<u8>>,
+    encodings: Vec<Encoding>,
+    pub(super) format: protocol::PixelFormat,
+    // What updates went out in so far, if any did; a new format takes
+    // effect at the next update, as it would coming from a server.
+    sent_format: Option<protocol::PixelFormat>,
+    translator: Option<Translator>,
+    encoder: zrle::Encoder,
+    requested: bool,
+    // Pseudo-rectangles of the next update, of which only the latest of each
+    // kind matters: the reason and status of a screen layout, a bare new
+    // size, the pointer position and whether the cursor shape goes along.
+    layout: Option<(u16, u16)>,
+    desktop_size: bool,
+    pointer: Option<(u16, u16)>,
+    cursor: bool,
+    copies: Vec<(Rect, Rect)>,
+    damage: Vec<Rect>,
+}
+
+impl Viewer {
+    // Everything is new to a viewer that just connected.
+    pub(super) fn new(messages: Sender<Vec<u8>>, desktop: &Desktop) -> Viewer {
+        let mut viewer = Viewer {
+            messages,
+            encodings: Vec::new(),
+            format: desktop.format(),
+            sent_format: None,
+            translator: None,
+            encoder: zrle::Encoder::new(),
+            requested: false,
+            layout: None,
+            desktop_size: false,
+            pointer: None,
+            cursor: false,
+            copies: Vec::new(),
+            damage: Vec::new(),
+        };
+        viewer.add_damage(Rect {
+            left: 0,
+            top: 0,
+            width: desktop.size.0,
+            height: desktop.size.1,
+        });
+        viewer
+    }
+
+    fn supports(&self, encoding: Encoding) -> bool {
+        self.encodings.contains(&encoding)
+    }
+
+    fn send<M: Message>(&self, message: M) -> Result<()> {
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer)?;
+        self.messages.send(buffer).map_err(|_| Error::Disconnected)
+    }
+
+    // The cursor and the screen layout, which a server only sends when they
+    // change, are sent again for the encodings that are new.
+    pub(super) fn set_encodings(
+        &mut self,
+        encodings: Vec<Encoding>,
+        desktop: &Desktop,
+    ) -> Result<()> {
+        let new = |encoding| encodings.contains(&encoding) && !self.supports(encoding);
+        let (cursor, layout) = (new(Encoding::Cursor), new(Encoding::ExtendedDesktopSize));
+        self.encodings = encodings;
+        if cursor {
+            self.cursor = desktop.cursor.is_some();
+        } else if !self.supports(Encoding::Cursor) {
+            self.cursor = false;
+        }
+        if layout && !desktop.screens.is_empty() {
+            self.layout = Some((0, 0));
+        }
+        Ok(())
+    }
+
+    fn add_damage(&mut self, mut rect: Rect) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        while let Some(i) = self.damage.iter().position(|d| touches(d, &rect)) {
+            rect = union(&self.damage.swap_remove(i), &rect);
+        }
+        self.damage.push(rect);
+        if self.damage.len() > MAX_DAMAGE {
+            let bounds = self.damage.iter().fold(rect, |a, b| union(&a, b));
+            self.damage = vec![bounds];
+        }
+    }
+
+    pub(super) fn change(&mut self, change: &Change, desktop: &Desktop) -> Result<()> {
+        match *change {
+            Change::Pixels(rect) => self.add_damage(rect),
+            Change::Copy { src, dst } => {
+                // The viewer applies copies ahead of the rest of the update,
+                // so one that reads from pixels changed earlier in it goes
+                // out as pixels.
+                if self.supports(Encoding::CopyRect)
+                    && self.copies.len() < MAX_DAMAGE
+                    && !self.damage.iter().any(|d| intersects(d, &src))
+                {
+                    self.copies.push((src, dst));
+                } else {
+                    self.add_damage(dst);
+                }
+            }
+            Change::ColourMap {
+                first_colour,
+                ref colours,
+            } => match self.translator {
+                Some(ref mut translator) => translator.set_colour_map(first_colour, colours),
+                // Sent whole with the first update otherwise.
+                None if self.sent_format.is_some_and(|format| !format.true_colour) => {
+                    self.send(protocol::S2C::SetColourMapEntries {
+                        first_colour,
+                        colours: colours.clone(),
+                    })?
+                }
+                None => (),
+            },
+            Change::Cursor => self.cursor = self.supports(Encoding::Cursor),
+            Change::Pointer(x, y) => {
+                if self.supports(Encoding::PointerPos) {
+                    self.pointer = Some((x, y));
+                }
+            }
+            Change::Resize => {
+                self.copies.clear();
+                self.damage.clear();
+                self.add_damage(Rect {
+                    left: 0,
+                    top: 0,
+                    width: desktop.size.0,
+                    height: desktop.size.1,
+                });
+                if self.supports(Encoding::ExtendedDesktopSize) && !desktop.screens.is_empty() {
+                    self.layout = Some((0, 0));
+                } else if self.supports(Encoding::DesktopSize) {
+                    self.desktop_size = true;
+                }
+            }
+            Change::Layout => {
+                if self.supports(Encoding::ExtendedDesktopSize) {
+                    self.layout = Some((0, 0));
+                }
+            }
+            // Reason 1: a reply to a viewer's own request, which any of them
+            // could have made.
+            Change::DesktopSizeRejected(status) => {
+                if self.supports(Encoding::ExtendedDesktopSize) {
+                    self.layout = Some((1, status));
+                }
+            }
+            Change::Clipboard(ref text) => self.send(protocol::S2C::CutText(text.clone()))?,
+            Change::Bell => self.send(protocol::S2C::Bell)?,
+            Change::EndOfFrame => (),
+        }
+        Ok(())
+    }
+
+    // Returns whether the viewer still waits for something to change.
+    pub(super) fn request(
+        &mut self,
+        incremental: bool,
+        rect: Rect,
+        desktop: &Desktop,
+    ) -> Result<bool> {
+        if !incremental {
+            self.add_damage(clip(rect, desktop.size));
+        }
+        self.requested = true;
+        self.update(desktop)
+    }
+
+    // Sends what changed if the viewer asked for it. Returns whether it
+    // still waits for something to change.
+    pub(super) fn update(&mut self, desktop: &Desktop) -> Result<bool> {
+        if !self.requested {
+            return Ok(false);
+        }
+        if !desktop.ready
+            || self.layout.is_none()
+                && !self.desktop_size
+                && self.pointer.is_none()
+                && !self.cursor
+                && self.copies.is_empty()
+                && self.damage.is_empty()
+                && self.sent_format == Some(self.format)
+        {
+            return Ok(true);
+        }
+        self.requested = false;
+        self.send_update(desktop)?;
+        Ok(false)
+    }
+
+    fn translate(&self, pixels: Vec<u8>) -> Vec<u8> {
+        match self.translator {
+            Some(ref translator) => translator.translate(&pixels),
+            None => pixels,
+        }
+    }
+
+    fn send_update(&mut self, desktop: &Desktop) -> Result<()> {
+        let mut update = Vec::new();
+
+        if self.sent_format != Some(self.format) {
+            debug!("c<-! switching to {:?}", self.format);
+            self.sent_format = Some(self.format);
+            self.translator = (self.format != desktop.format()).then(|| {
+                let mut translator = Translator::new(desktop.source_format(), self.format);
+                translator.set_colour_map(0, &desktop.colour_map);
+                translator
+            });
+            let colour_map = match self.translator {
+                Some(ref translator) => translator.client_colour_map(),
+                None if !self.format.true_colour => Some(desktop.colour_map.clone()),
+                None => None,
+            };
+            if let Some(colours) = colour_map {
+                protocol::S2C::SetColourMapEntries {
+                    first_colour: 0,
+                    colours,
+                }
+                .write_to(&mut update)?;
+            }
+        }
+
+        let cursor = desktop.cursor.as_ref().filter(|_| self.cursor);
+        let zrle = self.supports(Encoding::Zrle);
+        let count = self.layout.is_some() as usize
+            + self.desktop_size as usize
+            + self.pointer.is_some() as usize
+            + cursor.is_some() as usize
+            + self.copies.len()
+            + self.damage.len();
+        protocol::S2C::FramebufferUpdate {
+            count: count as u16,
+        }
+        .write_to(&mut update)?;
+        if let Some(reason_status) = self.layout.take() {
+            pseudo_rect(
+                &mut update,
+                reason_status,
+                desktop.size,
+                Encoding::ExtendedDesktopSize,
+            )?;
+            protocol::ScreenLayout(desktop.screens.clone()).write_to(&mut update)?;
+        }
+        if self.desktop_size {
+            pseudo_rect(&mut update, (0, 0), desktop.size, Encoding::DesktopSize)?;
+        }
+        self.desktop_size = false;
+        if let Some(position) = self.pointer.take() {
+            pseudo_rect(&mut update, position, (0, 0), Encoding::PointerPos)?;
+        }
+        if let Some((rectangle, pixels, mask_bits)) = cursor {
+            rectangle.write_to(&mut update)?;
+            update.write_all(&self.translate(pixels.clone()))?;
+            update.write_all(mask_bits)?;
+        }
+        self.cursor = false;
+
+        for (src, dst) in self.copies.drain(..) {
+            protocol::Rectangle {
+                x_position: dst.left,
+                y_position: dst.top,
+                width: dst.width,
+                height: dst.height,
+                encoding: Encoding::CopyRect,
+            }
+            .write_to(&mut update)?;
+            protocol::CopyRect {
+                src_x_position: src.left,
+                src_y_position: src.top,
+            }
+            .write_to(&mut update)?;
+        }
+
+        for rect in std::mem::take(&mut self.damage) {
+            protocol::Rectangle {
+                x_position: rect.left,
+                y_position: rect.top,
+                width: rect.width,
+                height: rect.height,
+                encoding: if zrle { Encoding::Zrle } else { Encoding::Raw },
+            }
+            .write_to(&mut update)?;
+            let pixels = self.translate(desktop.pixels(rect));
+            if zrle {
+                self.encoder
+                    .encode(self.format, rect.width, rect.height, &pixels)?
+                    .write_to(&mut update)?;
+            } else {
+                update.write_all(&pixels)?;
+            }
+        }
+
+        self.messages.send(update).map_err(|_| Error::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Viewer;
+    use crate::vnc::gray::GRAY8_FORMAT;
+    use crate::vnc::protocol::{self, Encoding, Message, S2C};
+    use crate::vnc::transcode::{Change, Desktop};
+    use crate::vnc::Rect;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_pseudo_rects_replaced() {
+        let mut desktop = Desktop::new(GRAY8_FORMAT, (2, 1), None);
+        desktop.ready = true;
+        let (messages, received) = mpsc::channel();
+        let mut viewer = Viewer::new(messages, &desktop);
+        viewer
+            .set_encodings(vec![Encoding::PointerPos], &desktop)
+            .unwrap();
+
+        // A viewer that doesn't ask for updates only ever has the latest
+        // pointer position waiting for it.
+        for x in 0..=u16::MAX {
+            viewer.change(&Change::Pointer(x, 1), &desktop).unwrap();
+        }
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+        assert!(!viewer.request(true, rect, &desktop).unwrap());
+
+        let update = received.try_recv().unwrap();
+        let mut update = &update[..];
+        assert!(matches!(
+            S2C::read_from(&mut update).unwrap(),
+            S2C::FramebufferUpdate { count: 2 }
+        ));
+        let pointer = protocol::Rectangle::read_from(&mut update).unwrap();
+        assert_eq!(pointer.encoding, Encoding::PointerPos);
+        assert_eq!((pointer.x_position, pointer.y_position), (u16::MAX, 1));
+        let pixels = protocol::Rectangle::read_from(&mut update).unwrap();
+        assert_eq!(pixels.encoding, Encoding::Raw);
+        assert_eq!(update.len(), 2);
+    }
+}
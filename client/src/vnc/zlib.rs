@@ -0,0 +1,87 @@
+use crate::vnc::zrle::ZlibReader;
+use crate::vnc::{protocol, Rect, Result};
+use std::io::Read;
+
+// The Zlib encoding is Raw pixels deflated through one zlib stream that
+// persists for the whole session.
+pub struct Decoder {
+    decompressor: Option<flate2::Decompress>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            decompressor: Some(flate2::Decompress::new(/*zlib_header*/ true)),
+        }
+    }
+
+    pub fn decode<F>(
+        &mut self,
+        format: protocol::PixelFormat,
+        rect: Rect,
+        input: &[u8],
+        mut callback: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+    {
+        let length =
+            (rect.width as usize) * (rect.height as usize) * (format.bits_per_pixel as usize / 8);
+        let mut reader = ZlibReader::new(self.decompressor.take().unwrap(), input);
+        let mut pixels = vec![0; length];
+        reader.read_exact(&mut pixels)?;
+        self.decompressor = Some(reader.into_inner()?);
+
+        callback(rect, pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decoder;
+    use crate::vnc::{protocol::PixelFormat, Rect};
+    use std::io::Write;
+
+    #[test]
+    fn test_decode_persistent_stream() {
+        let format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: false,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        let rect = Rect {
+            left: 1,
+            top: 2,
+            width: 3,
+            height: 4,
+        };
+
+        let mut compressor =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut decoder = Decoder::new();
+        for frame in 0..2u8 {
+            let pixels: Vec<u8> = (0..24).map(|i| i * (frame + 1)).collect();
+            let start = compressor.get_ref().len();
+            compressor.write_all(&pixels).unwrap();
+            compressor.flush().unwrap();
+            let input = compressor.get_ref()[start..].to_vec();
+
+            let mut decoded = Vec::new();
+            let result = decoder
+                .decode(format, rect, &input, |rect, pixels| {
+                    decoded.push((rect, pixels));
+                    Ok(true)
+                })
+                .unwrap();
+            assert!(result);
+            assert_eq!(decoded, vec![(rect, pixels)]);
+        }
+    }
+}
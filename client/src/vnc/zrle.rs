@@ -1,25 +1,26 @@
 use crate::vnc::{protocol, Error, Rect, Result};
 use byteorder::ReadBytesExt;
+use fxhash::FxHashMap;
 use std::io::Read;
 
-struct ZlibReader<'a> {
+pub(super) struct ZlibReader<'a> {
     decompressor: flate2::Decompress,
     input: &'a [u8],
 }
 
 impl<'a> ZlibReader<'a> {
-    fn new(decompressor: flate2::Decompress, input: &'a [u8]) -> ZlibReader<'a> {
+    pub(super) fn new(decompressor: flate2::Decompress, input: &'a [u8]) -> ZlibReader<'a> {
         ZlibReader {
             decompressor,
             input,
         }
     }
 
-    fn into_inner(self) -> Result<flate2::Decompress> {
+    pub(super) fn into_inner(self) -> Result<flate2::Decompress> {
         if self.input.is_empty() {
             Ok(self.decompressor)
         } else {
-            Err(Error::Unexpected("leftover ZRLE byte data"))
+            Err(Error::Unexpected("leftover zlib byte data"))
         }
     }
 }
@@ -41,7 +42,7 @@ impl<'a> Read for ZlibReader<'a> {
             Err(error) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error)),
             Ok(flate2::Status::StreamEnd) => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                "ZRLE stream end",
+                "zlib stream end",
             )),
         }
     }
@@ -130,176 +131,606 @@ impl Decoder {
         format: protocol::PixelFormat,
         rect: Rect,
         input: &[u8],
-        mut callback: F,
+        callback: F,
     ) -> Result<bool>
     where
         F: FnMut(Rect, Vec<u8>) -> Result<bool>,
     {
-        fn read_run_length(reader: &mut dyn Read) -> Result<usize> {
-            let mut run_length_part = reader.read_u8()?;
-            let mut run_length = 1 + run_length_part as usize;
-            while run_length_part == 255 {
-                run_length_part = reader.read_u8()?;
-                run_length += run_length_part as usize;
-            }
-            Ok(run_length)
+        let mut reader = BitReader::new(ZlibReader::new(self.decompressor.take().unwrap(), input));
+        if !decode_tiles(format, rect, 64, false, &mut reader, callback)? {
+            return Ok(false);
         }
 
-        fn copy_true_color(
-            reader: &mut dyn Read,
-            pixels: &mut Vec<u8>,
-            pad: bool,
-            compressed_bpp: usize,
-            bpp: usize,
-        ) -> Result<()> {
-            let mut buf = [0; 4];
-            reader.read_exact(&mut buf[pad as usize..pad as usize + compressed_bpp])?;
-            pixels.extend_from_slice(&buf[..bpp]);
-            Ok(())
+        self.decompressor = Some(reader.into_inner()?.into_inner()?);
+        Ok(true)
+    }
+}
+
+// How many bytes of a pixel go into a CPIXEL, and whether the one left
+// out comes first in memory. Only 32 bpp pixels with an unused byte lose it.
+fn compressed_pixel(format: protocol::PixelFormat) -> (usize, bool) {
+    let pixel_mask = (format.red_max as u32) << format.red_shift
+        | (format.green_max as u32) << format.green_shift
+        | (format.blue_max as u32) << format.blue_shift;
+
+    if format.bits_per_pixel == 32 && format.true_colour && format.depth <= 24 {
+        if pixel_mask & 0x000000ff == 0 {
+            (3, !format.big_endian)
+        } else if pixel_mask & 0xff000000 == 0 {
+            (3, format.big_endian)
+        } else {
+            (4, false)
         }
+    } else {
+        (format.bits_per_pixel as usize / 8, false)
+    }
+}
 
-        fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
-            let start = index as usize * bpp;
-            pixels.extend_from_slice(&palette[start..start + bpp])
+// Like decoding, all rectangles go through one zlib stream.
+pub struct Encoder {
+    compressor: flate2::Compress,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            compressor: flate2::Compress::new(
+                flate2::Compression::fast(),
+                /*zlib_header*/ true,
+            ),
         }
+    }
 
+    pub fn encode(
+        &mut self,
+        format: protocol::PixelFormat,
+        width: u16,
+        height: u16,
+        pixels: &[u8],
+    ) -> Result<Vec<u8>> {
         let bpp = format.bits_per_pixel as usize / 8;
-        let pixel_mask = (format.red_max as u32) << format.red_shift
-            | (format.green_max as u32) << format.green_shift
-            | (format.blue_max as u32) << format.blue_shift;
-
-        let (compressed_bpp, pad_pixel) =
-            if format.bits_per_pixel == 32 && format.true_colour && format.depth <= 24 {
-                if pixel_mask & 0x000000ff == 0 {
-                    (3, !format.big_endian)
-                } else if pixel_mask & 0xff000000 == 0 {
-                    (3, format.big_endian)
+        assert_eq!(pixels.len(), width as usize * height as usize * bpp);
+
+        // Tiles are worked on as CPIXELs packed into integers, in the
+        // order their bytes go out.
+        let (compressed_bpp, pad_pixel) = compressed_pixel(format);
+        let offset = pad_pixel as usize;
+        let stride = width as usize * bpp;
+        let mut tiles = Vec::new();
+        let mut tile = Vec::with_capacity(64 * 64);
+        for y in (0..height as usize).step_by(64) {
+            let tile_height = (height as usize - y).min(64);
+            for x in (0..width as usize).step_by(64) {
+                let tile_width = (width as usize - x).min(64);
+                tile.clear();
+                for row in pixels[y * stride..].chunks(stride).take(tile_height) {
+                    tile.extend(
+                        row[x * bpp..(x + tile_width) * bpp]
+                            .chunks(bpp)
+                            .map(|pixel| {
+                                pixel[offset..offset + compressed_bpp]
+                                    .iter()
+                                    .fold(0, |value, &byte| value << 8 | byte as u32)
+                            }),
+                    );
+                }
+                encode_tile(tile_width, &tile, compressed_bpp, &mut tiles);
+            }
+        }
+
+        // Sync flushes end every rectangle on a byte boundary, so the
+        // client can decode it without waiting for the next one.
+        let mut output = Vec::with_capacity(tiles.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+            let in_before = self.compressor.total_in();
+            self.compressor
+                .compress_vec(&tiles[consumed..], &mut output, flate2::FlushCompress::Sync)
+                .map_err(|_| Error::Unexpected("zlib compression failure"))?;
+            consumed += (self.compressor.total_in() - in_before) as usize;
+            if consumed == tiles.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+        Ok(output)
+    }
+}
+
+// Picks the smallest of a solid fill, packed palette indices, palette RLE
+// and raw pixels.
+fn encode_tile(width: usize, pixels: &[u32], compressed_bpp: usize, output: &mut Vec<u8>) {
+    let put_pixel = |output: &mut Vec<u8>, pixel: u32| {
+        output.extend_from_slice(&pixel.to_be_bytes()[4 - compressed_bpp..])
+    };
+
+    let mut palette = Vec::new();
+    let mut indices = FxHashMap::default();
+    for &pixel in pixels {
+        if palette.len() == 128 {
+            break;
+        }
+        indices.entry(pixel).or_insert_with(|| {
+            palette.push(pixel);
+            palette.len() as u8 - 1
+        });
+    }
+    match palette.len() {
+        1 => {
+            output.push(1);
+            return put_pixel(output, palette[0]);
+        }
+        // More than 127 colours don't fit a palette at all.
+        128 => {
+            output.push(0);
+            for &pixel in pixels {
+                put_pixel(output, pixel);
+            }
+            return;
+        }
+        _ => (),
+    }
+
+    let mut runs = Vec::new();
+    for &pixel in pixels {
+        match runs.last_mut() {
+            Some((value, length)) if *value == pixel => *length += 1,
+            _ => runs.push((pixel, 1usize)),
+        }
+    }
+    let palette_size = palette.len() * compressed_bpp;
+    let rle_size = palette_size
+        + runs
+            .iter()
+            .map(|&(_, length)| {
+                if length == 1 {
+                    1
                 } else {
-                    (4, false)
+                    2 + (length - 1) / 255
+                }
+            })
+            .sum::<usize>();
+    let bits_per_index = match palette.len() {
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 0,
+    };
+    let height = pixels.len() / width;
+    let packed_size = if bits_per_index > 0 {
+        palette_size + height * (width * bits_per_index).div_ceil(8)
+    } else {
+        usize::MAX
+    };
+
+    if pixels.len() * compressed_bpp <= rle_size.min(packed_size) {
+        output.push(0);
+        for &pixel in pixels {
+            put_pixel(output, pixel);
+        }
+    } else if packed_size <= rle_size {
+        output.push(palette.len() as u8);
+        for &pixel in &palette {
+            put_pixel(output, pixel);
+        }
+        for row in pixels.chunks(width) {
+            let mut byte = 0;
+            let mut position = 8;
+            for pixel in row {
+                position -= bits_per_index;
+                byte |= indices[pixel] << position;
+                if position == 0 {
+                    output.push(byte);
+                    byte = 0;
+                    position = 8;
                 }
+            }
+            if position < 8 {
+                output.push(byte);
+            }
+        }
+    } else {
+        output.push(128 | palette.len() as u8);
+        for &pixel in &palette {
+            put_pixel(output, pixel);
+        }
+        for (pixel, length) in runs {
+            let index = indices[&pixel];
+            if length == 1 {
+                output.push(index);
             } else {
-                (bpp, false)
-            };
+                output.push(128 | index);
+                let mut remaining = length - 1;
+                while remaining >= 255 {
+                    output.push(255);
+                    remaining -= 255;
+                }
+                output.push(remaining as u8);
+            }
+        }
+    }
+}
 
-        let mut palette = Vec::with_capacity(128 * bpp);
-        let mut reader = BitReader::new(ZlibReader::new(self.decompressor.take().unwrap(), input));
+// TRLE is ZRLE without the zlib wrapper, with 16x16 tiles and the ability
+// to reuse the palette of the previous tile.
+pub fn decode_trle<R, F>(
+    format: protocol::PixelFormat,
+    rect: Rect,
+    reader: &mut R,
+    callback: F,
+) -> Result<bool>
+where
+    R: Read,
+    F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+{
+    let mut reader = BitReader::new(reader);
+    if !decode_tiles(format, rect, 16, true, &mut reader, callback)? {
+        return Ok(false);
+    }
 
-        let mut y = 0;
-        while y < rect.height {
-            let height = if y + 64 > rect.height {
-                rect.height - y
-            } else {
-                64
-            };
-            let mut x = 0;
-            while x < rect.width {
-                let width = if x + 64 > rect.width {
-                    rect.width - x
-                } else {
-                    64
-                };
-                let pixel_count = height as usize * width as usize;
+    reader.into_inner()?;
+    Ok(true)
+}
+
+fn decode_tiles<R, F>(
+    format: protocol::PixelFormat,
+    rect: Rect,
+    tile_size: u16,
+    reuse_palette: bool,
+    reader: &mut BitReader<R>,
+    mut callback: F,
+) -> Result<bool>
+where
+    R: Read,
+    F: FnMut(Rect, Vec<u8>) -> Result<bool>,
+{
+    fn read_run_length(reader: &mut dyn Read) -> Result<usize> {
+        let mut run_length_part = reader.read_u8()?;
+        let mut run_length = 1 + run_length_part as usize;
+        while run_length_part == 255 {
+            run_length_part = reader.read_u8()?;
+            run_length += run_length_part as usize;
+        }
+        Ok(run_length)
+    }
+
+    fn copy_true_color(
+        reader: &mut dyn Read,
+        pixels: &mut Vec<u8>,
+        pad: bool,
+        compressed_bpp: usize,
+        bpp: usize,
+    ) -> Result<()> {
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf[pad as usize..pad as usize + compressed_bpp])?;
+        pixels.extend_from_slice(&buf[..bpp]);
+        Ok(())
+    }
+
+    fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) -> Result<()> {
+        let start = index as usize * bpp;
+        let pixel = palette
+            .get(start..start + bpp)
+            .ok_or(Error::Unexpected("ZRLE palette index"))?;
+        pixels.extend_from_slice(pixel);
+        Ok(())
+    }
+
+    // Runs may not spill over into the next tile.
+    fn check_run_length(run_length: usize, left: usize) -> Result<()> {
+        if run_length > left {
+            return Err(Error::Unexpected("ZRLE run length"));
+        }
+        Ok(())
+    }
+
+    let bpp = format.bits_per_pixel as usize / 8;
+    let (compressed_bpp, pad_pixel) = compressed_pixel(format);
 
-                let is_rle = reader.read_bit()?;
-                let palette_size = reader.read_bits(7)?;
+    let mut palette = Vec::with_capacity(128 * bpp);
+    let mut palette_size = 0;
 
-                palette.truncate(0);
-                for _ in 0..palette_size {
-                    copy_true_color(&mut reader, &mut palette, pad_pixel, compressed_bpp, bpp)?
+    let mut y = 0;
+    while y < rect.height {
+        let height = tile_size.min(rect.height - y);
+        let mut x = 0;
+        while x < rect.width {
+            let width = tile_size.min(rect.width - x);
+            let pixel_count = height as usize * width as usize;
+
+            let is_rle = reader.read_bit()?;
+            let subencoding = reader.read_bits(7)?;
+
+            match (is_rle, subencoding) {
+                (false, 127) | (true, 1) if reuse_palette => (),
+                _ => {
+                    palette_size = subencoding;
+                    palette.truncate(0);
+                    for _ in 0..palette_size {
+                        copy_true_color(reader, &mut palette, pad_pixel, compressed_bpp, bpp)?
+                    }
                 }
+            }
 
-                let mut pixels = Vec::with_capacity(pixel_count * bpp);
-                match (is_rle, palette_size) {
-                    (false, 0) => {
-                        // True Color pixels
-                        for _ in 0..pixel_count {
-                            copy_true_color(
-                                &mut reader,
-                                &mut pixels,
-                                pad_pixel,
-                                compressed_bpp,
-                                bpp,
-                            )?
-                        }
+            let mut pixels = Vec::with_capacity(pixel_count * bpp);
+            match (is_rle, palette_size) {
+                (false, 0) => {
+                    // True Color pixels
+                    for _ in 0..pixel_count {
+                        copy_true_color(reader, &mut pixels, pad_pixel, compressed_bpp, bpp)?
                     }
-                    (false, 1) => {
-                        // Color fill
-                        for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
-                        }
+                }
+                (false, 1) => {
+                    // Color fill
+                    for _ in 0..pixel_count {
+                        copy_indexed(&palette, &mut pixels, bpp, 0)?
                     }
-                    (false, 2) | (false, 3..=4) | (false, 5..=16) => {
-                        // Indexed pixels
-                        let bits_per_index = match palette_size {
-                            2 => 1,
-                            3..=4 => 2,
-                            5..=16 => 4,
-                            _ => unreachable!(),
-                        };
-                        for _ in 0..height {
-                            for _ in 0..width {
-                                let index = reader.read_bits(bits_per_index)?;
-                                copy_indexed(&palette, &mut pixels, bpp, index)
-                            }
-                            reader.align();
+                }
+                (false, 2) | (false, 3..=4) | (false, 5..=16) => {
+                    // Indexed pixels
+                    let bits_per_index = match palette_size {
+                        2 => 1,
+                        3..=4 => 2,
+                        5..=16 => 4,
+                        _ => unreachable!(),
+                    };
+                    for _ in 0..height {
+                        for _ in 0..width {
+                            let index = reader.read_bits(bits_per_index)?;
+                            copy_indexed(&palette, &mut pixels, bpp, index)?
                         }
+                        reader.align();
                     }
-                    (true, 0) => {
-                        // True Color RLE
-                        let mut count = 0;
-                        let mut pixel = Vec::new();
-                        while count < pixel_count {
-                            pixel.truncate(0);
-                            copy_true_color(
-                                &mut reader,
-                                &mut pixel,
-                                pad_pixel,
-                                compressed_bpp,
-                                bpp,
-                            )?;
-                            let run_length = read_run_length(&mut reader)?;
-                            for _ in 0..run_length {
-                                pixels.extend(&pixel)
-                            }
-                            count += run_length;
+                }
+                (true, 0) => {
+                    // True Color RLE
+                    let mut count = 0;
+                    let mut pixel = Vec::new();
+                    while count < pixel_count {
+                        pixel.truncate(0);
+                        copy_true_color(reader, &mut pixel, pad_pixel, compressed_bpp, bpp)?;
+                        let run_length = read_run_length(reader)?;
+                        check_run_length(run_length, pixel_count - count)?;
+                        for _ in 0..run_length {
+                            pixels.extend(&pixel)
                         }
+                        count += run_length;
                     }
-                    (true, 2..=127) => {
-                        // Indexed RLE
-                        let mut count = 0;
-                        while count < pixel_count {
-                            let longer_than_one = reader.read_bit()?;
-                            let index = reader.read_bits(7)?;
-                            let run_length = if longer_than_one {
-                                read_run_length(&mut reader)?
-                            } else {
-                                1
-                            };
-                            for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
-                            }
-                            count += run_length;
+                }
+                (true, 2..=127) => {
+                    // Indexed RLE
+                    let mut count = 0;
+                    while count < pixel_count {
+                        let longer_than_one = reader.read_bit()?;
+                        let index = reader.read_bits(7)?;
+                        let run_length = if longer_than_one {
+                            read_run_length(reader)?
+                        } else {
+                            1
+                        };
+                        check_run_length(run_length, pixel_count - count)?;
+                        for _ in 0..run_length {
+                            copy_indexed(&palette, &mut pixels, bpp, index)?;
                         }
+                        count += run_length;
                     }
-                    _ => return Err(Error::Unexpected("ZRLE subencoding")),
-                }
-
-                let tile = Rect {
-                    top: rect.top + y,
-                    left: rect.left + x,
-                    width,
-                    height,
-                };
-                if let false = callback(tile, pixels)? {
-                    return Ok(false);
                 }
+                _ => return Err(Error::Unexpected("ZRLE subencoding")),
+            }
 
-                x += width;
+            let tile = Rect {
+                top: rect.top + y,
+                left: rect.left + x,
+                width,
+                height,
+            };
+            if !callback(tile, pixels)? {
+                return Ok(false);
             }
-            y += height;
+
+            x += width;
         }
+        y += height;
+    }
 
-        self.decompressor = Some(reader.into_inner()?.into_inner()?);
-        Ok(true)
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_trle, Decoder, Encoder};
+    use crate::vnc::{protocol::PixelFormat, Rect};
+
+    #[test]
+    fn test_decode_trle_palette_reuse() {
+        let format = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian: false,
+            true_colour: true,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 0,
+            green_shift: 3,
+            blue_shift: 6,
+        };
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 17,
+            height: 1,
+        };
+        let input: &[u8] = &[
+            // Packed palette of two colours, one bit per pixel.
+            0x02,
+            0x10,
+            0x20,
+            0b1100_0000,
+            0b0000_0001,
+            // Packed palette reused from the previous tile.
+            0x7f,
+            0b1000_0000,
+        ];
+
+        let mut tiles = Vec::new();
+        let result = decode_trle(format, rect, &mut &input[..], |tile, pixels| {
+            tiles.push((tile, pixels));
+            Ok(true)
+        })
+        .unwrap();
+        assert!(result);
+
+        let mut expected = vec![0x10; 16];
+        expected[0] = 0x20;
+        expected[1] = 0x20;
+        expected[15] = 0x20;
+        assert_eq!(
+            tiles,
+            vec![
+                (
+                    Rect {
+                        left: 0,
+                        top: 0,
+                        width: 16,
+                        height: 1
+                    },
+                    expected
+                ),
+                (
+                    Rect {
+                        left: 16,
+                        top: 0,
+                        width: 1,
+                        height: 1
+                    },
+                    vec![0x20]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_trle_out_of_bounds() {
+        let format = crate::vnc::gray::GRAY8_FORMAT;
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+        for input in [
+            // RLE with a palette of two and an index of 100.
+            &[0x82, 1, 2, 100, 100][..],
+            // A run of 201 pixels in a tile of two.
+            &[0x80, 1, 200][..],
+        ] {
+            let result = decode_trle(format, rect, &mut &input[..], |_, _| Ok(true));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let format = crate::vnc::gray::GRAY8_FORMAT;
+        let (width, height) = (150u16, 70u16);
+        // A solid tile, two-colour stripes, sixteen levels and a gradient
+        // too colourful for a palette.
+        let pixels: Vec<u8> = (0..height as usize)
+            .flat_map(|y| {
+                (0..width as usize).map(move |x| match x / 64 {
+                    0 if y < 64 => 0x80,
+                    0 => [0x00, 0xff][x % 2],
+                    1 => (x % 16 * 17) as u8,
+                    _ => (x * 7 + y) as u8,
+                })
+            })
+            .collect();
+
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        // The second rectangle continues the zlib stream of the first.
+        for _ in 0..2 {
+            let data = encoder.encode(format, width, height, &pixels).unwrap();
+            let mut decoded = vec![0; pixels.len()];
+            let rect = Rect {
+                left: 0,
+                top: 0,
+                width,
+                height,
+            };
+            let result = decoder
+                .decode(format, rect, &data, |tile, tile_pixels| {
+                    for (row, line) in tile_pixels.chunks(tile.width as usize).enumerate() {
+                        let start = (tile.top as usize + row) * width as usize + tile.left as usize;
+                        decoded[start..start + line.len()].copy_from_slice(line);
+                    }
+                    Ok(true)
+                })
+                .unwrap();
+            assert!(result);
+            assert_eq!(decoded, pixels);
+        }
+    }
+
+    #[test]
+    fn test_encode_compressed_pixels() {
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 70,
+            height: 3,
+        };
+        for big_endian in [false, true] {
+            // The unused byte is left out of every CPIXEL, whichever end
+            // of the pixel it's at.
+            let format = PixelFormat {
+                bits_per_pixel: 32,
+                depth: 24,
+                big_endian,
+                true_colour: true,
+                red_max: 255,
+                green_max: 255,
+                blue_max: 255,
+                red_shift: 16,
+                green_shift: 8,
+                blue_shift: 0,
+            };
+            let pixels: Vec<u8> = (0..rect.width as usize * rect.height as usize)
+                .flat_map(|i| {
+                    let value = [0x00ff_0000u32, 0x0000_ff00, 0x0012_3456][i % 3] + i as u32 / 64;
+                    if big_endian {
+                        value.to_be_bytes()
+                    } else {
+                        value.to_le_bytes()
+                    }
+                })
+                .collect();
+
+            let data = Encoder::new()
+                .encode(format, rect.width, rect.height, &pixels)
+                .unwrap();
+            let mut tiles = Vec::new();
+            Decoder::new()
+                .decode(format, rect, &data, |tile, tile_pixels| {
+                    tiles.push((tile, tile_pixels));
+                    Ok(true)
+                })
+                .unwrap();
+            let decoded: Vec<u8> = (0..rect.height as usize)
+                .flat_map(|row| {
+                    tiles
+                        .iter()
+                        .flat_map(move |(tile, tile_pixels)| {
+                            let stride = tile.width as usize * 4;
+                            tile_pixels[row * stride..(row + 1) * stride].to_vec()
+                        })
+                        .collect::<Vec<u8>>()
+                })
+                .collect();
+            assert_eq!(decoded, pixels);
+        }
     }
 }